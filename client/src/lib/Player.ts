@@ -7,6 +7,11 @@ import { Mp4Info, Mp4Parser } from "./Mp4Parser";
 
 export const RENDER_BUFFER_SIZE_MS = 100;
 
+// set in the frame type byte of frames the player can't decode
+const UNDECODABLE_FLAG = 0x80;
+// set in the frame type byte of the first frame after the source restarted or looped
+const DISCONTINUITY_FLAG = 0x40;
+
 export type StartAt = { mode: "live" } | { mode: "future"; group: number };
 
 type PlayerState = "PAUSED" | "BUFFERING" | "PLAYING";
@@ -57,6 +62,8 @@ export class Player extends EventTarget {
   reorderBuffer: RawFrame[] = [];
 
   processing: Map<number, RawFrame> = new Map();
+  // whether the decoder has to be recreated, because the source restarted
+  resetDecoder: boolean = false;
 
   logger: Logger;
   mp4Parser: Mp4Parser;
@@ -134,7 +141,19 @@ export class Player extends EventTarget {
     return new TransformStream<Uint8Array, RawFrame>({
       transform: (object, controller) => {
         const view = new DataView(object.buffer);
-        const frameType = ["P", "B", "I"][view.getUint8(0)] as "P" | "B" | "I";
+        // the server marks frames sent before the first keyframe when joining at the live edge
+        const undecodable = (view.getUint8(0) & UNDECODABLE_FLAG) !== 0;
+        if (undecodable) {
+          return;
+        }
+        if ((view.getUint8(0) & DISCONTINUITY_FLAG) !== 0) {
+          // frames of the previous source won't be decoded anymore
+          this.processing.clear();
+          this.resetDecoder = true;
+        }
+        const frameType = ["P", "B", "I"][
+          view.getUint8(0) & ~(UNDECODABLE_FLAG | DISCONTINUITY_FLAG)
+        ] as "P" | "B" | "I";
         const availabilityTime = Number(view.getBigUint64(1, false));
         if (!this.firstFrameAvailabilityTime) {
           this.firstFrameAvailabilityTime = availabilityTime / 1_000_000;
@@ -161,6 +180,11 @@ export class Player extends EventTarget {
     // maps frames timestamps (presentation time) to the frame's availability time
     return new TransformStream<ParsedFrame, Frame>({
       transform: async (encodedFrame, controller) => {
+        if (videoDecoder && this.resetDecoder) {
+          videoDecoder.close();
+          videoDecoder = undefined;
+        }
+        this.resetDecoder = false;
         if (!videoDecoder) {
           videoDecoder = new VideoDecoder({
             output: (videoFrame: VideoFrame) => {
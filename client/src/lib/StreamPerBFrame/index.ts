@@ -44,6 +44,8 @@ export class StreamPerBFrame extends Player {
     console.log("subscribed to video track");
     this.videoTrackSubscriptionId = videoTrackSub.subscribeId;
 
+    // sent as objects by the b-frame mapping and as datagrams by the datagram mapping,
+    // either way every object carries a whole frame
     const bFramesTrackSub = await this.session.subscribe(
       this.namespace,
       "b-frames",
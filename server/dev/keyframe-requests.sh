@@ -0,0 +1,23 @@
+#!/bin/bash
+
+# Consumes the keyframe requests the server writes to --keyframe-requests, one per line,
+# either "keyframe" or "keyframe <rendition>".
+# KEYFRAME_COMMAND is run for every request with the rendition (if any) as argument,
+# e.g. a script telling the encoder to insert an IDR frame.
+
+set -euo pipefail
+
+FIFO="${1:?usage: $0 <fifo>}"
+
+[ -p "$FIFO" ] || mkfifo "$FIFO"
+
+# the server reopens the FIFO after it was restarted, so read it again after EOF
+while true; do
+	while read -r request rendition; do
+		[ "$request" = "keyframe" ] || continue
+		echo "keyframe requested: ${rendition:-all renditions}" >&2
+		if [ -n "${KEYFRAME_COMMAND:-}" ]; then
+			$KEYFRAME_COMMAND ${rendition:+"$rendition"} || echo "keyframe command failed" >&2
+		fi
+	done < "$FIFO"
+done
@@ -24,6 +24,12 @@ fi
 # Default to a source video
 INPUT="${INPUT:-dev/bbb.mp4}"
 
+# Consume the server's keyframe requests (--keyframe-requests) if a FIFO is given
+if [ -n "${KEYFRAME_REQUESTS:-}" ]; then
+	./dev/keyframe-requests.sh "$KEYFRAME_REQUESTS" &
+	trap 'kill $!' EXIT
+fi
+
 # Run ffmpeg and pipe the output to moq-pub
 ffmpeg -hide_banner \
     -v quiet \
@@ -4,8 +4,13 @@ import (
 	"bufio"
 	"bytes"
 	"encoding/binary"
+	"hash/crc32"
 )
 
+// syncMarker precedes every item, and every item is followed by the CRC-32 (IEEE) of the item,
+// so the server can find the next item after corrupt input
+var syncMarker = []byte("MOQI")
+
 // func main() {
 // 	reader := bufio.NewReader(os.Stdin)
 // 	initCh, framesCh := newFragmentedMp4(reader).parseBoxes()
@@ -24,7 +29,21 @@ func main() {
 	test()
 }
 
-func serializeInit(writer *bufio.Writer, init bytes.Buffer) {
+// writeItem writes an item framed by the sync marker and its CRC
+func writeItem(writer *bufio.Writer, item *bytes.Buffer) {
+	if _, err := writer.Write(syncMarker); err != nil {
+		panic(err)
+	}
+	if _, err := writer.Write(item.Bytes()); err != nil {
+		panic(err)
+	}
+	if err := binary.Write(writer, binary.BigEndian, crc32.ChecksumIEEE(item.Bytes())); err != nil {
+		panic(err)
+	}
+}
+
+func serializeInit(output *bufio.Writer, init bytes.Buffer) {
+	writer := new(bytes.Buffer)
 	// Write null byte (0x00)
 	if err := writer.WriteByte(0x00); err != nil {
 		panic(err)
@@ -38,10 +57,12 @@ func serializeInit(writer *bufio.Writer, init bytes.Buffer) {
 	if _, err := writer.Write(init.Bytes()); err != nil {
 		panic(err)
 	}
-	writer.Flush()
+	writeItem(output, writer)
+	output.Flush()
 }
 
-func serializeEncodedFrame(writer *bufio.Writer, frame encodedFrame) {
+func serializeEncodedFrame(output *bufio.Writer, frame encodedFrame) {
+	writer := new(bytes.Buffer)
 	// Write frame identifier byte (0x01)
 	if err := writer.WriteByte(0x01); err != nil {
 		panic(err)
@@ -73,6 +94,7 @@ func serializeEncodedFrame(writer *bufio.Writer, frame encodedFrame) {
 	if _, err := writer.Write(frame.data.Bytes()); err != nil {
 		panic(err)
 	}
+	writeItem(output, writer)
 }
 
 func boolToByte(b bool) byte {
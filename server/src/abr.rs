@@ -0,0 +1,193 @@
+use bytes::Bytes;
+use futures::{stream::FuturesUnordered, StreamExt};
+use moq_transport::serve::{StreamGroupReader, StreamWriter, TrackReaderMode, TracksReader};
+use tokio::sync::watch;
+
+use crate::{
+    broadcast::{read_catalog, read_first},
+    egress::Egress,
+    stats::SessionStats,
+    video::{parse_frame_header, FrameType},
+};
+
+// only switch to a rendition if the estimated throughput exceeds its bitrate by this factor
+const HEADROOM: f64 = 1.25;
+
+// A virtual track (video/auto) that forwards the groups of the rendition that best fits
+// the subscriber's estimated throughput. Renditions are only switched at group boundaries.
+// Every group starts with the init segment of the rendition it was taken from,
+// followed by the rendition's frames. The groups of the renditions are aligned on the
+// decode time of their first frame, which the renditions of a broadcast share.
+pub struct Auto {
+    tracks: TracksReader,
+    stats: SessionStats,
+    egress: Option<Egress>,
+}
+
+struct Source {
+    name: String,
+    // in kbit/s
+    bitrate: u32,
+    init: Option<Bytes>,
+    // the latest group of the rendition and the decode time of its first frame
+    latest: watch::Receiver<Option<(u64, StreamGroupReader)>>,
+}
+
+impl Auto {
+    pub fn new(tracks: TracksReader, stats: SessionStats) -> Self {
+        Self {
+            tracks,
+            stats,
+            egress: None,
+        }
+    }
+
+    pub fn with_egress(mut self, egress: Egress) -> Self {
+        self.egress = Some(egress);
+        self
+    }
+
+    // estimated throughput in kbit/s
+    fn throughput(&self) -> f64 {
+        self.stats.get().capacity() * 8.0 / 1000.0
+    }
+
+    // the rendition with the highest bitrate that fits the throughput, or the lowest one
+    fn select(&self, sources: &[Source]) -> usize {
+        let throughput = self.throughput();
+        sources
+            .iter()
+            .enumerate()
+            .filter(|(_, source)| source.bitrate as f64 * HEADROOM <= throughput)
+            .max_by_key(|(_, source)| source.bitrate)
+            .or_else(|| {
+                sources
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, source)| source.bitrate)
+            })
+            .map(|(index, _)| index)
+            .unwrap_or(0)
+    }
+
+    pub async fn forward(mut self, track: &str, writer: StreamWriter) -> anyhow::Result<()> {
+        let catalog = read_catalog(&mut self.tracks).await?;
+
+        let mut sources = Vec::new();
+        let mut watchers = FuturesUnordered::new();
+        for rendition in catalog.renditions {
+            let (Some(name), Some(bitrate)) = (rendition.name, rendition.bitrate) else {
+                continue;
+            };
+            let reader = self
+                .tracks
+                .subscribe(&format!("{}/{}", track, name))
+                .ok_or_else(|| anyhow::anyhow!("Missing track for rendition {}", name))?;
+            let stream = match reader.mode().await? {
+                TrackReaderMode::Stream(stream) => stream,
+                _ => return Err(anyhow::anyhow!("Only stream tracks can be switched")),
+            };
+
+            let (tx, latest) = watch::channel(None);
+            watchers.push(async move {
+                let mut stream = stream;
+                while let Some(group) = stream.next().await? {
+                    let Some(first) = group.clone().read_next().await? else {
+                        continue;
+                    };
+                    let decode_time = parse_frame_header(&first)?.decode_time;
+                    tx.send_replace(Some((decode_time, group)));
+                }
+                anyhow::Ok(())
+            });
+
+            sources.push(Source {
+                name,
+                bitrate,
+                init: None,
+                latest,
+            });
+        }
+        if sources.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No renditions with a bitrate in the catalog"
+            ));
+        }
+
+        tokio::select! {
+            res = self.switch(&mut sources, writer) => res,
+            Some(res) = watchers.next() => res,
+        }
+    }
+
+    // Waits until the frame can be sent within the egress limits, the track is sent with
+    // the lowest priority. Returns false if the frame is dropped.
+    async fn admit(&self, bytes: usize, droppable: bool) -> bool {
+        match &self.egress {
+            Some(egress) => egress.acquire(0, bytes, droppable).await,
+            None => true,
+        }
+    }
+
+    async fn switch(
+        &mut self,
+        sources: &mut [Source],
+        mut writer: StreamWriter,
+    ) -> anyhow::Result<()> {
+        let mut current: Option<usize> = None;
+        // the decode time of the last forwarded group
+        let mut last_start: Option<u64> = None;
+
+        loop {
+            let index = self.select(sources);
+            if current != Some(index) {
+                log::info!(
+                    "switching to rendition {} at {:.0} kbit/s",
+                    sources[index].name,
+                    self.throughput()
+                );
+                current = Some(index);
+            }
+
+            let init = match sources[index].init.clone() {
+                Some(init) => init,
+                None => {
+                    let name = format!("init/{}", sources[index].name);
+                    let init = read_first(&mut self.tracks, &name).await?;
+                    sources[index].init.insert(init).clone()
+                }
+            };
+
+            // wait for a group that started after the last forwarded one
+            let source = &mut sources[index];
+            let (started, mut group) = loop {
+                if let Some((started, group)) = source.latest.borrow_and_update().clone() {
+                    if last_start.is_none_or(|last| started > last) {
+                        break (started, group);
+                    }
+                }
+                source.latest.changed().await?;
+            };
+
+            let mut output = writer.append()?;
+            self.admit(init.len(), false).await;
+            self.stats.enqueued(init.len());
+            output.write(init)?;
+            let mut first = true;
+            while let Some(frame) = group.read_next().await? {
+                // the group can't be decoded without its first frame, the others can be dropped
+                if !self.admit(frame.len(), !first).await {
+                    // the following frames of the group depend on anything but a B-frame
+                    match parse_frame_header(&frame) {
+                        Ok(header) if header.frame_type == FrameType::B => continue,
+                        _ => break,
+                    }
+                }
+                first = false;
+                self.stats.enqueued(frame.len());
+                output.write(frame)?;
+            }
+            last_start = Some(started);
+        }
+    }
+}
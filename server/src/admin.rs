@@ -0,0 +1,300 @@
+use std::{fmt, net::SocketAddr, time::Duration};
+
+use anyhow::Context;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+};
+
+use crate::{broadcasts::Command, local::Locals, sessions::Sessions};
+
+// requests with larger headers are rejected
+const MAX_REQUEST_SIZE: usize = 8 << 10;
+// a stalled client's connection is closed after this long
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// An error answered with its own status, other errors of a request are answered with 409.
+#[derive(Debug)]
+pub enum RequestError {
+    // the request is invalid, e.g. a session id that isn't a number
+    BadRequest(String),
+    // there is no such broadcast, track or session
+    NotFound(String),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::BadRequest(message) | RequestError::NotFound(message) => {
+                write!(f, "{}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+// A local HTTP/JSON API to inspect and control the server:
+//
+//   GET  /broadcasts                                  the broadcasts, their tracks and latest ids
+//   POST /broadcasts/{namespace}/start                start a configured broadcast
+//   POST /broadcasts/{namespace}/stop                 stop a broadcast
+//   POST /broadcasts/{namespace}/tracks/{name}/group  start a new group at the next keyframe
+//   GET  /sessions                                    the sessions, their subscriptions and stats
+//   POST /sessions/{id}/kick                          close a session
+//
+// There is no authentication, so it should only listen on a local address.
+#[derive(Clone)]
+pub struct Admin {
+    locals: Locals,
+    sessions: Sessions,
+    commands: mpsc::Sender<Command>,
+}
+
+impl Admin {
+    pub fn new(locals: Locals, sessions: Sessions, commands: mpsc::Sender<Command>) -> Self {
+        Self {
+            locals,
+            sessions,
+            commands,
+        }
+    }
+
+    pub async fn run(self, bind: SocketAddr) -> anyhow::Result<()> {
+        let listener = TcpListener::bind(bind)
+            .await
+            .with_context(|| format!("failed to bind admin API to {}", bind))?;
+        log::info!("admin API listening on {}", bind);
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let admin = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = admin.serve(stream).await {
+                    log::warn!("failed to serve admin request: {:#}", err);
+                }
+            });
+        }
+    }
+
+    async fn serve(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Request timed out")));
+        let (status, body) = match request {
+            Ok((method, path)) => {
+                log::debug!("admin request: {} {}", method, path);
+                response(self.handle(&method, &path).await)
+            }
+            Err(err) => (400, json!({ "error": format!("{:#}", err) })),
+        };
+
+        let body = body.to_string();
+        let response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            reason(status),
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    // returns None if there is no such resource
+    async fn handle(&self, method: &str, path: &str) -> anyhow::Result<Option<Value>> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            ("GET", ["broadcasts"]) => Ok(Some(self.broadcasts())),
+            ("GET", ["sessions"]) => Ok(Some(self.sessions.list())),
+            ("POST", ["sessions", id, "kick"]) => {
+                let id: usize = id
+                    .parse()
+                    .map_err(|_| RequestError::BadRequest("Invalid session id".to_string()))?;
+                Ok(self.sessions.kick(id).then(|| json!({ "kicked": id })))
+            }
+            ("POST", ["broadcasts", namespace, "start"]) => {
+                self.command(|reply| Command::Start(namespace.to_string(), reply))
+                    .await?;
+                Ok(Some(json!({ "started": namespace })))
+            }
+            ("POST", ["broadcasts", namespace, "stop"]) => {
+                self.command(|reply| Command::Stop(namespace.to_string(), reply))
+                    .await?;
+                Ok(Some(json!({ "stopped": namespace })))
+            }
+            // track names may contain slashes, e.g. video/720p
+            ("POST", ["broadcasts", namespace, "tracks", name @ .., "group"])
+                if !name.is_empty() =>
+            {
+                let name = name.join("/");
+                let Some(cache) = self.locals.cache(namespace, &name) else {
+                    return Ok(None);
+                };
+                cache.split()?;
+                // the new group starts with the next keyframe, requested so it comes soon
+                let keyframes = self.locals.keyframes(namespace, &name).await?;
+                if let Some(keyframes) = &keyframes {
+                    keyframes.request();
+                }
+                Ok(Some(json!({
+                    "namespace": namespace,
+                    "track": name,
+                    "keyframe_requested": keyframes.is_some(),
+                })))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn broadcasts(&self) -> Value {
+        let mut namespaces = self.locals.namespaces();
+        namespaces.sort();
+        namespaces
+            .into_iter()
+            .map(|namespace| {
+                let mut caches = self.locals.caches(&namespace);
+                caches.sort_by(|(a, _), (b, _)| a.cmp(b));
+                let tracks: Vec<Value> = caches
+                    .into_iter()
+                    .map(|(name, cache)| {
+                        let latest = cache.latest();
+                        json!({
+                            "name": name,
+                            "mode": format!("{:?}", cache.mode).to_lowercase(),
+                            "group_id": latest.map(|(group_id, _)| group_id),
+                            "object_id": latest.map(|(_, object_id)| object_id),
+                        })
+                    })
+                    .collect();
+                let settings = self.locals.settings(&namespace).unwrap_or_default();
+                json!({
+                    "namespace": namespace,
+                    "tracks": tracks,
+                    "join": format!("{:?}", settings.forward.join),
+                    "priority": format!("{:?}", settings.forward.priority),
+                    "drop_budget_ms": settings.forward.drop_budget.map(|budget| budget.as_millis() as u64),
+                    "keyframe_requests": settings.keyframes.is_some(),
+                })
+            })
+            .collect()
+    }
+
+    // sends a command to the broadcasts and waits for the result
+    async fn command(
+        &self,
+        command: impl FnOnce(oneshot::Sender<anyhow::Result<()>>) -> Command,
+    ) -> anyhow::Result<()> {
+        let (reply, result) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .await
+            .map_err(|_| anyhow::anyhow!("Server shutting down"))?;
+        result
+            .await
+            .map_err(|_| anyhow::anyhow!("Server shutting down"))?
+    }
+}
+
+// the status and body of the response to a handled request
+fn response(handled: anyhow::Result<Option<Value>>) -> (u16, Value) {
+    match handled {
+        Ok(Some(body)) => (200, body),
+        Ok(None) => (404, json!({ "error": "not found" })),
+        Err(err) => {
+            let status = match err.downcast_ref::<RequestError>() {
+                Some(RequestError::BadRequest(_)) => 400,
+                Some(RequestError::NotFound(_)) => 404,
+                None => 409,
+            };
+            (status, json!({ "error": format!("{:#}", err) }))
+        }
+    }
+}
+
+// reads the request line and headers, returns the method and path
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<(String, String)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|window| window == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Err(anyhow::anyhow!("Request too large"));
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(anyhow::anyhow!("Incomplete request"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&buf);
+    let mut parts = request.lines().next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(anyhow::anyhow!("Invalid request line"));
+    };
+    // the query string isn't used
+    let path = target.split('?').next().unwrap_or_default();
+    Ok((method.to_string(), path.to_string()))
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        409 => "Conflict",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::broadcasts::Broadcasts;
+
+    // handles a request, with the commands handled by broadcasts without any configured
+    async fn request(method: &str, path: &str) -> u16 {
+        let (commands, mut received) = mpsc::channel(1);
+        let locals = Locals::new();
+        let admin = Admin::new(locals.clone(), Sessions::default(), commands);
+        let mut broadcasts = Broadcasts::new(locals);
+
+        let handled = admin.handle(method, path);
+        tokio::pin!(handled);
+        let handled = tokio::select! {
+            handled = &mut handled => handled,
+            Some(command) = received.recv() => {
+                broadcasts.handle(command).await;
+                handled.await
+            }
+        };
+        response(handled).0
+    }
+
+    #[tokio::test]
+    async fn invalid_session_id_is_bad_request() {
+        assert_eq!(request("POST", "/sessions/abc/kick").await, 400);
+    }
+
+    #[tokio::test]
+    async fn unknown_ids_are_not_found() {
+        assert_eq!(request("POST", "/sessions/7/kick").await, 404);
+        assert_eq!(request("POST", "/broadcasts/live/start").await, 404);
+        assert_eq!(request("POST", "/broadcasts/live/stop").await, 404);
+        assert_eq!(
+            request("POST", "/broadcasts/live/tracks/video/group").await,
+            404
+        );
+        assert_eq!(request("GET", "/unknown").await, 404);
+    }
+
+    #[test]
+    fn other_errors_are_conflicts() {
+        let failed = Err(anyhow::anyhow!("Broadcast already running"));
+        assert_eq!(response(failed).0, 409);
+        assert_eq!(response(Ok(Some(json!({})))).0, 200);
+    }
+}
@@ -0,0 +1,231 @@
+mod report;
+mod source;
+mod subscriber;
+
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+use moq_streaming_server_rs::{
+    broadcasts::Broadcasts,
+    config::{AuthConfig, BroadcastConfig, EgressConfig, ImpairConfig, Limits, LoggingConfig},
+    ingest::Input,
+    local::Locals,
+    mappings::Mapping,
+    server::{Server, ServerConfig},
+    sessions::Sessions,
+    shutdown::Drain,
+};
+use report::Report;
+use source::SourceConfig;
+use url::Url;
+
+const NAMESPACE: &str = "bench";
+// added to the duration of a GOP before the subscribers join
+const WARMUP_MARGIN: Duration = Duration::from_millis(500);
+// the delay of runs without any impairment, so they still go through the impairment layer
+const DEFAULT_DELAY: u64 = 10;
+
+// Compares the mappings: for each trace and mapping, the synthetic video is broadcast by
+// an in-process server over loopback, through the impairment layer, to in-process subscribers.
+#[derive(Parser)]
+struct Cli {
+    /// The address the server listens on, the port is incremented for each run
+    #[arg(long, default_value = "127.0.0.1:4443")]
+    bind: SocketAddr,
+    /// The TLS configuration, self-signed certificates need --tls-disable-verify.
+    #[command(flatten)]
+    tls: moq_native::tls::Args,
+    /// Compare these mappings (repeatable), all of them by default
+    #[arg(long = "mapping", value_enum)]
+    mappings: Vec<Mapping>,
+    /// Run each mapping with each of these bandwidth traces (repeatable),
+    /// otherwise with the impairments alone
+    #[arg(long = "trace")]
+    traces: Vec<PathBuf>,
+    /// The impairments of every run, a delay of 10 ms if none is given.
+    #[command(flatten)]
+    impairment: ImpairConfig,
+    /// The synthetic video.
+    #[command(flatten)]
+    source: SourceConfig,
+    /// The rendition the subscribers receive, the first one in the catalog by default
+    #[arg(long)]
+    rendition: Option<String>,
+    /// In-process subscribers per run
+    #[arg(long, default_value_t = 1)]
+    subscribers: usize,
+    /// How long the subscribers receive the broadcast in each run, in seconds
+    #[arg(long, default_value_t = 30)]
+    duration: u64,
+    /// Frames arriving later than this many milliseconds after their ingest are late
+    #[arg(long, default_value_t = 1000)]
+    latency_target: u64,
+    /// Drop frames that can't arrive within this many milliseconds of their presentation time
+    #[arg(long)]
+    frame_deadline: Option<u64>,
+    /// Drop frames when a subscriber's queue delay exceeds this many milliseconds
+    #[arg(long)]
+    drop_budget: Option<u64>,
+    /// Also write the report as JSON to this file
+    #[arg(long)]
+    report: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    // the ingest isn't Send, so the broadcasts run on this thread
+    tokio::task::LocalSet::new()
+        .run_until(run(Cli::parse()))
+        .await
+}
+
+async fn run(cli: Cli) -> anyhow::Result<()> {
+    let tls = cli.tls.load()?;
+    if tls.server.is_none() {
+        anyhow::bail!("missing TLS certificates");
+    }
+
+    let mappings = match cli.mappings.is_empty() {
+        true => Mapping::value_variants().to_vec(),
+        false => cli.mappings.clone(),
+    };
+    let traces = match cli.traces.is_empty() {
+        true => vec![cli.impairment.trace.clone()],
+        false => cli.traces.iter().cloned().map(Some).collect(),
+    };
+
+    let mut reports = Vec::new();
+    let mut port = cli.bind.port();
+    for trace in &traces {
+        for mapping in &mappings {
+            let mapping_name = mapping
+                .to_possible_value()
+                .map(|value| value.get_name().to_string())
+                .unwrap_or_default();
+            let trace_name = trace.as_deref().map(trace_name);
+            log::info!(
+                "benchmarking mapping={} trace={:?}",
+                mapping_name,
+                trace_name
+            );
+
+            let bind = SocketAddr::new(cli.bind.ip(), port);
+            port += 1;
+            let subscriptions = bench(&cli, &tls, bind, *mapping, trace.clone())
+                .await
+                .with_context(|| format!("failed to benchmark {}", mapping_name))?;
+            // the subscribers all receive the same rendition
+            let rendition = subscriptions
+                .first()
+                .and_then(|subscription| subscription.rendition.clone());
+            let received = subscriptions
+                .into_iter()
+                .map(|subscription| subscription.frames)
+                .collect();
+
+            reports.push(Report::new(
+                mapping_name,
+                trace_name,
+                rendition,
+                received,
+                cli.source.frame_duration(),
+                Duration::from_millis(cli.latency_target),
+            ));
+        }
+    }
+
+    report::print(&reports);
+    if let Some(path) = &cli.report {
+        let json = serde_json::to_vec_pretty(&reports)?;
+        std::fs::write(path, json)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+// runs a mapping with a trace, returns what each subscriber received
+async fn bench(
+    cli: &Cli,
+    tls: &moq_native::tls::Config,
+    bind: SocketAddr,
+    mapping: Mapping,
+    trace: Option<PathBuf>,
+) -> anyhow::Result<Vec<subscriber::Subscription>> {
+    let socket = std::env::temp_dir().join(format!(
+        "moq-bench-{}-{}.sock",
+        std::process::id(),
+        bind.port()
+    ));
+    std::fs::remove_file(&socket).ok();
+
+    let mut impairment = ImpairConfig {
+        trace,
+        ..cli.impairment.clone()
+    };
+    if !impairment.enabled() {
+        impairment.delay = DEFAULT_DELAY;
+    }
+
+    let (_draining, drain) = Drain::new(None, Duration::ZERO);
+    let config = ServerConfig {
+        listeners: vec![bind],
+        tls: tls.clone(),
+        auth: AuthConfig::default(),
+        limits: Limits::default(),
+        egress: EgressConfig::default(),
+        impairment,
+        logging: LoggingConfig::default(),
+        drain,
+    };
+    let locals = Locals::new();
+    let server = Server::new(config, locals.clone(), Sessions::default()).await?;
+
+    let mut broadcasts = Broadcasts::new(locals);
+    broadcasts
+        .apply(&[BroadcastConfig {
+            namespace: NAMESPACE.to_string(),
+            mapping,
+            frame_deadline: cli.frame_deadline,
+            drop_budget: cli.drop_budget,
+            input: Input::Socket(socket.clone()),
+            ..Default::default()
+        }])
+        .await;
+
+    let url = Url::parse(&format!("https://{}", bind))?;
+    let duration = Duration::from_secs(cli.duration);
+    // the subscribers join once the first GOP was ingested
+    let warmup = Duration::from_secs_f64(cli.source.gop as f64 / cli.source.fps.max(1) as f64);
+    let rendition = cli.rendition.as_deref();
+    let subscribers = async {
+        tokio::time::sleep(warmup + WARMUP_MARGIN).await;
+        let subscribers = (0..cli.subscribers)
+            .map(|_| subscriber::run(tls.clone(), &url, NAMESPACE, rendition, duration));
+        futures::future::try_join_all(subscribers).await
+    };
+
+    let res = tokio::select! {
+        res = server.run() => res.and_then(|_| Err(anyhow::anyhow!("Server stopped"))),
+        res = source::run(cli.source, &socket) => {
+            res.and_then(|_| Err(anyhow::anyhow!("Source stopped")))
+        }
+        res = subscribers => res,
+    };
+
+    broadcasts.stop_all().await;
+    std::fs::remove_file(&socket).ok();
+    res
+}
+
+fn trace_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.display().to_string())
+}
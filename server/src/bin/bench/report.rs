@@ -0,0 +1,194 @@
+use std::{collections::HashSet, time::Duration};
+
+use serde::Serialize;
+
+use crate::{source, subscriber::Received};
+
+// frames missing or late for at least this long count as a stall,
+// shorter gaps only count as skipped frames
+const STALL_THRESHOLD: Duration = Duration::from_millis(100);
+
+// The results of a mapping and trace, summed over the subscribers.
+//
+// A frame is played if it arrives within the latency target of its ingest, like a
+// player with a fixed latency. Frames arriving later are late, and their bytes wasted.
+// Frames never received, or received but undecodable, are skipped.
+#[derive(Serialize)]
+pub struct Report {
+    pub mapping: String,
+    pub trace: Option<String>,
+    // the rendition the subscribers received
+    pub rendition: Option<String>,
+    pub subscribers: usize,
+    pub frames: usize,
+    // from the ingest of a frame to its arrival at the subscriber
+    pub latency_p50_ms: f64,
+    pub latency_p90_ms: f64,
+    pub latency_p99_ms: f64,
+    pub late_frames: u64,
+    pub stalls: u64,
+    pub skipped_frames: u64,
+    pub wasted_bytes: u64,
+}
+
+impl Report {
+    pub fn new(
+        mapping: String,
+        trace: Option<String>,
+        rendition: Option<String>,
+        subscribers: Vec<Vec<Received>>,
+        frame_duration: u64,
+        latency_target: Duration,
+    ) -> Self {
+        let mut report = Self {
+            mapping,
+            trace,
+            rendition,
+            subscribers: subscribers.len(),
+            frames: 0,
+            latency_p50_ms: 0.0,
+            latency_p90_ms: 0.0,
+            latency_p99_ms: 0.0,
+            late_frames: 0,
+            stalls: 0,
+            skipped_frames: 0,
+            wasted_bytes: 0,
+        };
+
+        let mut latencies = Vec::new();
+        for frames in subscribers {
+            report.add(frames, frame_duration, latency_target, &mut latencies);
+        }
+
+        latencies.sort_by(f64::total_cmp);
+        report.latency_p50_ms = percentile(&latencies, 0.5);
+        report.latency_p90_ms = percentile(&latencies, 0.9);
+        report.latency_p99_ms = percentile(&latencies, 0.99);
+        report
+    }
+
+    fn add(
+        &mut self,
+        mut frames: Vec<Received>,
+        frame_duration: u64,
+        latency_target: Duration,
+        latencies: &mut Vec<f64>,
+    ) {
+        // in decode order, without duplicates
+        frames.sort_by_key(|frame| frame.decode_time);
+        let mut seen = HashSet::new();
+        frames.retain(|frame| seen.insert(frame.decode_time));
+        let (Some(first), Some(last)) = (frames.first(), frames.last()) else {
+            return;
+        };
+        let expected = (last.decode_time - first.decode_time) / frame_duration.max(1) + 1;
+        self.frames += frames.len();
+        self.skipped_frames += expected.saturating_sub(frames.len() as u64);
+
+        let stall_frames = (STALL_THRESHOLD.as_secs_f64() * source::TIMESCALE as f64
+            / frame_duration as f64)
+            .ceil() as u64;
+        // frames not played in a row
+        let mut missed = 0;
+        let mut next = first.decode_time;
+        for frame in &frames {
+            missed += frame.decode_time.saturating_sub(next) / frame_duration.max(1);
+            next = frame.decode_time + frame_duration;
+
+            let latency = frame
+                .arrival
+                .duration_since(frame.availability)
+                .unwrap_or_default();
+            latencies.push(latency.as_secs_f64() * 1000.0);
+
+            let late = latency > latency_target;
+            if late {
+                self.late_frames += 1;
+                self.wasted_bytes += frame.size as u64;
+            }
+            if frame.undecodable {
+                self.skipped_frames += 1;
+            }
+
+            if late || frame.undecodable {
+                missed += 1;
+            } else {
+                if missed >= stall_frames {
+                    self.stalls += 1;
+                }
+                missed = 0;
+            }
+        }
+        if missed >= stall_frames {
+            self.stalls += 1;
+        }
+    }
+}
+
+fn percentile(sorted: &[f64], quantile: f64) -> f64 {
+    match sorted.len() {
+        0 => 0.0,
+        len => sorted[((len - 1) as f64 * quantile).round() as usize],
+    }
+}
+
+pub fn print(reports: &[Report]) {
+    println!(
+        "{:<12} {:<24} {:<12} {:>8} {:>9} {:>9} {:>9} {:>6} {:>7} {:>8} {:>12}",
+        "mapping",
+        "trace",
+        "rendition",
+        "frames",
+        "p50 ms",
+        "p90 ms",
+        "p99 ms",
+        "late",
+        "stalls",
+        "skipped",
+        "wasted bytes"
+    );
+    for report in reports {
+        println!(
+            "{:<12} {:<24} {:<12} {:>8} {:>9.1} {:>9.1} {:>9.1} {:>6} {:>7} {:>8} {:>12}",
+            report.mapping,
+            report.trace.as_deref().unwrap_or("-"),
+            report.rendition.as_deref().unwrap_or("-"),
+            report.frames,
+            report.latency_p50_ms,
+            report.latency_p90_ms,
+            report.latency_p99_ms,
+            report.late_frames,
+            report.stalls,
+            report.skipped_frames,
+            report.wasted_bytes,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentile_of_sorted_values() {
+        let values: Vec<f64> = (1..=11).map(f64::from).collect();
+        assert_eq!(percentile(&values, 0.0), 1.0);
+        assert_eq!(percentile(&values, 0.5), 6.0);
+        assert_eq!(percentile(&values, 0.9), 10.0);
+        assert_eq!(percentile(&values, 1.0), 11.0);
+    }
+
+    #[test]
+    fn percentile_rounds_to_nearest_rank() {
+        let values = [10.0, 20.0, 30.0, 40.0];
+        // rank 1.5 rounds up
+        assert_eq!(percentile(&values, 0.5), 30.0);
+        assert_eq!(percentile(&values, 0.99), 40.0);
+    }
+
+    #[test]
+    fn percentile_of_few_values() {
+        assert_eq!(percentile(&[], 0.5), 0.0);
+        assert_eq!(percentile(&[7.0], 0.99), 7.0);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::{BufMut, BytesMut};
+use moq_streaming_server_rs::video::FrameType;
+use tokio::{io::AsyncWriteExt, net::UnixStream};
+
+pub const TIMESCALE: u32 = 90000;
+
+// relative frame sizes within a GOP
+const I_FRAME_WEIGHT: u64 = 8;
+const P_FRAME_WEIGHT: u64 = 2;
+const B_FRAME_WEIGHT: u64 = 1;
+
+// A synthetic video, so runs don't depend on an encoder.
+#[derive(clap::Args, Clone, Copy, Debug)]
+pub struct SourceConfig {
+    /// Frames per second of the synthetic video
+    #[arg(long, default_value_t = 30)]
+    pub fps: u32,
+    /// Frames per GOP
+    #[arg(long, default_value_t = 60)]
+    pub gop: u32,
+    /// B-frames between two anchor frames
+    #[arg(long, default_value_t = 2)]
+    pub b_frames: u32,
+    /// Bitrate of the synthetic video, in kbit/s
+    #[arg(long, default_value_t = 2500)]
+    pub bitrate: u64,
+}
+
+impl SourceConfig {
+    // in TIMESCALE units
+    pub fn frame_duration(&self) -> u64 {
+        (TIMESCALE / self.fps.max(1)) as u64
+    }
+
+    // the frame types in decode order and their index in presentation order
+    fn gop_frames(&self) -> Vec<(FrameType, u64)> {
+        let last = self.gop.max(1) as u64 - 1;
+        let mut frames = vec![(FrameType::I, 0)];
+        let mut previous = 0;
+        while previous < last {
+            let anchor = last.min(previous + self.b_frames as u64 + 1);
+            frames.push((FrameType::P, anchor));
+            frames.extend((previous + 1..anchor).map(|index| (FrameType::B, index)));
+            previous = anchor;
+        }
+        frames
+    }
+
+    fn frame_sizes(&self, frames: &[(FrameType, u64)]) -> [usize; 3] {
+        let weight = |frame_type: FrameType| match frame_type {
+            FrameType::I => I_FRAME_WEIGHT,
+            FrameType::P => P_FRAME_WEIGHT,
+            FrameType::B => B_FRAME_WEIGHT,
+        };
+        let total: u64 = frames
+            .iter()
+            .map(|(frame_type, _)| weight(*frame_type))
+            .sum();
+        let gop_bytes = self.bitrate * 1000 / 8 * frames.len() as u64 / self.fps.max(1) as u64;
+        let size = |frame_type| (gop_bytes * weight(frame_type) / total).max(1) as usize;
+        [size(FrameType::P), size(FrameType::B), size(FrameType::I)]
+    }
+}
+
+// Connects to the ingest's unix socket and writes the synthetic video in real time,
+// in the format produced by the mp4-parser.
+pub async fn run(config: SourceConfig, socket: &Path) -> anyhow::Result<()> {
+    let mut stream = loop {
+        match UnixStream::connect(socket).await {
+            Ok(stream) => break stream,
+            // the ingest isn't listening yet
+            Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+    };
+
+    let init = init_segment();
+    let mut buf = BytesMut::new();
+    buf.put_u8(0x00);
+    buf.put_u32(init.len() as u32);
+    buf.extend_from_slice(&init);
+    stream.write_all(&buf).await?;
+
+    let frames = config.gop_frames();
+    let sizes = config.frame_sizes(&frames);
+    let duration = config.frame_duration();
+    let interval = Duration::from_secs_f64(1.0 / config.fps.max(1) as f64);
+    // presentation times are offset so they never precede the decode times
+    let delay = config.b_frames as u64 + 1;
+
+    let started = Instant::now();
+    let mut decode_index = 0u64;
+    loop {
+        let gop_start = decode_index;
+        for (frame_type, presentation_index) in &frames {
+            tokio::time::sleep_until((started + interval * decode_index as u32).into()).await;
+
+            let availability = SystemTime::now().duration_since(UNIX_EPOCH)?;
+            let size = sizes[*frame_type as usize];
+            buf.clear();
+            buf.put_u8(0x01);
+            buf.put_u8((*frame_type == FrameType::I) as u8);
+            buf.put_u8(*frame_type as u8);
+            buf.put_u64(availability.as_nanos() as u64);
+            buf.put_u64(decode_index * duration);
+            buf.put_u64((gop_start + presentation_index + delay) * duration);
+            buf.put_u32(size as u32);
+            buf.put_bytes(0, size);
+            stream.write_all(&buf).await?;
+
+            decode_index += 1;
+        }
+    }
+}
+
+// a minimal init segment (ftyp and moov) carrying the timescale
+fn init_segment() -> BytesMut {
+    let mut mdhd = BytesMut::new();
+    // version 0, flags, creation and modification times
+    mdhd.put_bytes(0, 4 + 4 + 4);
+    mdhd.put_u32(TIMESCALE);
+    // duration, language and pre-defined
+    mdhd.put_bytes(0, 4 + 2 + 2);
+
+    let mut init = mp4_box(b"ftyp", b"isom\0\0\0\0isomiso6");
+    let mdia = mp4_box(b"mdia", &mp4_box(b"mdhd", &mdhd));
+    let trak = mp4_box(b"trak", &mdia);
+    init.extend_from_slice(&mp4_box(b"moov", &trak));
+    init
+}
+
+fn mp4_box(kind: &[u8; 4], body: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(8 + body.len());
+    buf.put_u32(8 + body.len() as u32);
+    buf.put_slice(kind);
+    buf.put_slice(body);
+    buf
+}
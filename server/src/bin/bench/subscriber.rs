@@ -0,0 +1,214 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use futures::{stream::FuturesUnordered, StreamExt};
+use moq_native::quic;
+use moq_streaming_server_rs::{
+    broadcast::Catalog,
+    mappings::Reassembler,
+    video::{parse_frame_header, UNDECODABLE_FLAG},
+};
+use moq_transport::{
+    serve::{GroupReader, ObjectReader, Track, TrackReader, TrackReaderMode},
+    session::Subscriber,
+};
+use tokio::task::JoinSet;
+use url::Url;
+
+// A frame received by a subscriber.
+pub struct Received {
+    pub decode_time: u64,
+    pub availability: SystemTime,
+    pub arrival: SystemTime,
+    pub size: usize,
+    // sent after a join at the live edge, before the next keyframe
+    pub undecodable: bool,
+}
+
+type Frames = Arc<Mutex<Vec<Received>>>;
+
+// The frames a subscriber received from the video tracks of a rendition.
+pub struct Subscription {
+    pub rendition: Option<String>,
+    pub frames: Vec<Received>,
+}
+
+// Subscribes to the video tracks of the given rendition, or the first one listed in the
+// catalog, and records the frames received for the given duration.
+pub async fn run(
+    tls: moq_native::tls::Config,
+    url: &Url,
+    namespace: &str,
+    rendition: Option<&str>,
+    duration: Duration,
+) -> anyhow::Result<Subscription> {
+    let bind: SocketAddr = match url.host() {
+        Some(url::Host::Ipv6(_)) => "[::]:0".parse()?,
+        _ => "0.0.0.0:0".parse()?,
+    };
+    let quic = quic::Endpoint::new(quic::Config { bind, tls })?;
+    let session = quic.client.connect(url).await?;
+    let (session, subscriber) = Subscriber::connect(session)
+        .await
+        .context("failed to create MoQ session")?;
+
+    // the subscriptions end once the tasks are dropped
+    let mut tasks = JoinSet::new();
+    tasks.spawn(async move {
+        if let Err(err) = session.run().await {
+            log::debug!("MoQ session ended: {}", err);
+        }
+    });
+
+    let frames = Frames::default();
+    let selected = Mutex::new(None);
+    let subscribe = async {
+        let catalog = subscribe(&subscriber, namespace, "catalog", &mut tasks);
+        let catalog = read_catalog(catalog).await?;
+        let found = match rendition {
+            Some(rendition) => catalog
+                .renditions
+                .iter()
+                .find(|r| r.name.as_deref() == Some(rendition))
+                .with_context(|| format!("no rendition {} in catalog", rendition))?,
+            None => catalog
+                .renditions
+                .first()
+                .context("no renditions in catalog")?,
+        };
+        *selected.lock().unwrap() = found.name.clone();
+        for name in &found.tracks {
+            if name == "init" || name.starts_with("init/") {
+                continue;
+            }
+            let track = subscribe(&subscriber, namespace, name, &mut tasks);
+            let frames = frames.clone();
+            tasks.spawn(async move {
+                if let Err(err) = read(track, &frames).await {
+                    log::debug!("failed to read track: {}", err);
+                }
+            });
+        }
+        std::future::pending::<anyhow::Result<()>>().await
+    };
+    if let Ok(Err(err)) = tokio::time::timeout(duration, subscribe).await {
+        return Err(err);
+    }
+    drop(tasks);
+
+    let frames = std::mem::take(&mut *frames.lock().unwrap());
+    Ok(Subscription {
+        rendition: selected.into_inner().unwrap(),
+        frames,
+    })
+}
+
+fn subscribe(
+    subscriber: &Subscriber,
+    namespace: &str,
+    name: &str,
+    tasks: &mut JoinSet<()>,
+) -> TrackReader {
+    let (writer, reader) = Track::new(namespace.to_string(), name.to_string()).produce();
+    let mut subscriber = subscriber.clone();
+    let name = name.to_string();
+    tasks.spawn(async move {
+        if let Err(err) = subscriber.subscribe(writer).await {
+            log::debug!("subscription to {} ended: {}", name, err);
+        }
+    });
+    reader
+}
+
+async fn read_catalog(track: TrackReader) -> anyhow::Result<Catalog> {
+    let mut stream = match track.mode().await? {
+        TrackReaderMode::Stream(stream) => stream,
+        _ => anyhow::bail!("unexpected mode for catalog track"),
+    };
+    let mut group = stream.next().await?.context("catalog track ended")?;
+    let data = group.read_next().await?.context("catalog track ended")?;
+    serde_json::from_slice(&data).context("failed to parse catalog")
+}
+
+// reads the groups and objects of a track concurrently, so a stalled stream
+// doesn't delay the frames received on the others
+async fn read(track: TrackReader, frames: &Frames) -> anyhow::Result<()> {
+    match track.mode().await? {
+        TrackReaderMode::Stream(mut stream) => {
+            while let Some(mut group) = stream.next().await? {
+                while let Some(payload) = group.read_next().await? {
+                    record(frames, &payload);
+                }
+            }
+        }
+        TrackReaderMode::Groups(mut groups) => {
+            let mut reading = FuturesUnordered::new();
+            loop {
+                tokio::select! {
+                    group = groups.next() => match group? {
+                        Some(group) => reading.push(read_group(group, frames)),
+                        None => break,
+                    },
+                    Some(res) = reading.next() => res?,
+                }
+            }
+            while let Some(res) = reading.next().await {
+                res?;
+            }
+        }
+        TrackReaderMode::Objects(mut objects) => {
+            let mut reading = FuturesUnordered::new();
+            loop {
+                tokio::select! {
+                    object = objects.next() => match object? {
+                        Some(object) => reading.push(read_object(object, frames)),
+                        None => break,
+                    },
+                    Some(res) = reading.next() => res?,
+                }
+            }
+            while let Some(res) = reading.next().await {
+                res?;
+            }
+        }
+        TrackReaderMode::Datagrams(mut datagrams) => {
+            let mut reassembler = Reassembler::default();
+            while let Some(datagram) = datagrams.read().await? {
+                if let Some(payload) = reassembler.push(datagram.payload)? {
+                    record(frames, &payload);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn read_group(mut group: GroupReader, frames: &Frames) -> anyhow::Result<()> {
+    while let Some(payload) = group.read_next().await? {
+        record(frames, &payload);
+    }
+    Ok(())
+}
+
+async fn read_object(mut object: ObjectReader, frames: &Frames) -> anyhow::Result<()> {
+    let payload = object.read_all().await?;
+    record(frames, &payload);
+    Ok(())
+}
+
+fn record(frames: &Frames, payload: &[u8]) {
+    let Ok(header) = parse_frame_header(payload) else {
+        return;
+    };
+    frames.lock().unwrap().push(Received {
+        decode_time: header.decode_time,
+        availability: header.availability_time,
+        arrival: SystemTime::now(),
+        size: payload.len(),
+        undecodable: payload[0] & UNDECODABLE_FLAG != 0,
+    });
+}
@@ -0,0 +1,98 @@
+use anyhow::Context;
+use bytes::Bytes;
+use moq_transport::serve::{
+    StreamGroupWriter, TrackReaderMode, TrackWriter, TracksReader, TracksWriter,
+};
+use serde::{Deserialize, Serialize};
+
+// Describes the renditions of a broadcast and the tracks they consist of,
+// published as JSON on the catalog track.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Catalog {
+    pub renditions: Vec<CatalogRendition>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CatalogRendition {
+    pub name: Option<String>,
+    // in kbit/s
+    pub bitrate: Option<u32>,
+    pub tracks: Vec<String>,
+}
+
+pub struct Broadcast {
+    tracks: TracksWriter,
+    catalog: Catalog,
+    catalog_track: Option<StreamGroupWriter>,
+}
+
+impl Broadcast {
+    pub fn new(tracks: TracksWriter) -> Self {
+        Self {
+            tracks,
+            catalog: Catalog::default(),
+            catalog_track: None,
+        }
+    }
+
+    pub fn rendition(&mut self, name: Option<String>, bitrate: Option<u32>) -> Rendition<'_> {
+        self.catalog.renditions.push(CatalogRendition {
+            name,
+            bitrate,
+            tracks: Vec::new(),
+        });
+        Rendition { broadcast: self }
+    }
+
+    pub fn publish_catalog(&mut self) -> anyhow::Result<()> {
+        let catalog_track = match self.catalog_track.as_mut() {
+            Some(catalog_track) => catalog_track,
+            None => self.catalog_track.insert(
+                self.tracks
+                    .create("catalog")
+                    .ok_or_else(|| anyhow::anyhow!("Failed to create catalog track"))?
+                    .stream(0)?
+                    .append()?,
+            ),
+        };
+        catalog_track.write(serde_json::to_vec(&self.catalog)?.into())?;
+        Ok(())
+    }
+}
+
+// Creates the tracks of a single rendition.
+// Track names are suffixed with the name of the rendition, e.g. video/1080.
+pub struct Rendition<'a> {
+    broadcast: &'a mut Broadcast,
+}
+
+impl Rendition<'_> {
+    pub fn create(&mut self, name: &str) -> Option<TrackWriter> {
+        let rendition = self.broadcast.catalog.renditions.last_mut()?;
+        let name = match &rendition.name {
+            Some(rendition) => format!("{}/{}", name, rendition),
+            None => name.to_string(),
+        };
+        let track = self.broadcast.tracks.create(&name)?;
+        rendition.tracks.push(name);
+        Some(track)
+    }
+}
+
+pub async fn read_catalog(tracks: &mut TracksReader) -> anyhow::Result<Catalog> {
+    let data = read_first(tracks, "catalog").await?;
+    serde_json::from_slice(&data).context("failed to parse catalog")
+}
+
+// reads the first object of a track with a single stream group, like the init or catalog track
+pub async fn read_first(tracks: &mut TracksReader, name: &str) -> anyhow::Result<Bytes> {
+    let track = tracks
+        .subscribe(name)
+        .ok_or_else(|| anyhow::anyhow!("Missing track {}", name))?;
+    let mut stream = match track.mode().await? {
+        TrackReaderMode::Stream(stream) => stream,
+        _ => return Err(anyhow::anyhow!("Unexpected mode for track {}", name)),
+    };
+    let mut group = stream.next().await?.context("track ended")?;
+    group.read_next().await?.context("track ended")
+}
@@ -0,0 +1,235 @@
+use std::{collections::HashMap, time::Duration};
+
+use moq_transport::serve::{Tracks, TracksWriter};
+use tokio::{sync::oneshot, task::JoinHandle};
+
+use crate::{
+    admin::RequestError,
+    broadcast::Broadcast,
+    config::BroadcastConfig,
+    ingest,
+    keyframe::KeyframeRequests,
+    local::{BroadcastSettings, Locals},
+    mappings::{
+        DatagramPerBFrame, Mapping, StreamPerBFrame, StreamPerFrame, StreamPerFrameType,
+        StreamPerGop, StreamPerMiniGop, StreamPerTrack, TrackPerTemporalLayer,
+    },
+    video::VideoStreamer,
+};
+
+// The broadcasts being ingested and served. Each broadcast runs in its own task,
+// so broadcasts can be added and removed without affecting the others.
+// Must be used within a LocalSet, since the ingest isn't Send.
+pub struct Broadcasts {
+    locals: Locals,
+    // the configured broadcasts, which may have been stopped through the admin API
+    configs: Vec<BroadcastConfig>,
+    running: HashMap<String, Running>,
+}
+
+struct Running {
+    config: BroadcastConfig,
+    task: JoinHandle<()>,
+}
+
+impl Broadcasts {
+    pub fn new(locals: Locals) -> Self {
+        Self {
+            locals,
+            configs: Vec::new(),
+            running: HashMap::new(),
+        }
+    }
+
+    // Starts, stops and restarts broadcasts to match the configuration.
+    // Broadcasts whose configuration didn't change keep running,
+    // broadcasts stopped through the admin API are started again.
+    pub async fn apply(&mut self, configs: &[BroadcastConfig]) {
+        self.configs = configs.to_vec();
+
+        let stale: Vec<String> = self
+            .running
+            .iter()
+            .filter(|(namespace, running)| {
+                !configs
+                    .iter()
+                    .any(|config| &config.namespace == *namespace && config == &running.config)
+            })
+            .map(|(namespace, _)| namespace.clone())
+            .collect();
+        for namespace in stale {
+            self.stop(&namespace).await;
+        }
+
+        for config in configs {
+            if self.running.contains_key(&config.namespace) {
+                continue;
+            }
+            if let Err(err) = self.start(config.clone()).await {
+                log::warn!("failed to start broadcast {}: {:#}", config.namespace, err);
+            }
+        }
+    }
+
+    async fn start(&mut self, config: BroadcastConfig) -> anyhow::Result<()> {
+        let (writer, _, reader) = Tracks::new(config.namespace.clone()).produce();
+
+        let (keyframes, requester) = match config.keyframe_requests.clone() {
+            Some(path) => {
+                let interval = Duration::from_millis(config.keyframe_request_interval);
+                let (keyframes, requester) = KeyframeRequests::new(path, interval);
+                (Some(keyframes), Some(requester))
+            }
+            None => (None, None),
+        };
+
+        let settings = BroadcastSettings {
+            forward: config.forward(),
+            keyframes,
+        };
+        let registration = self.locals.register(reader, settings).await?;
+
+        let namespace = config.namespace.clone();
+        let ingest = run(config.clone(), writer);
+        let task = tokio::task::spawn_local(async move {
+            // the broadcast is unregistered when the task ends or is aborted
+            let _registration = registration;
+            let requests = async {
+                match requester {
+                    Some(requester) => requester.run().await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            let res = tokio::select! {
+                res = ingest => res,
+                res = requests => res,
+            };
+            if let Err(err) = res {
+                log::warn!("broadcast {} failed: {:#}", namespace, err);
+            }
+        });
+
+        log::info!("started broadcast {}", config.namespace);
+        self.running
+            .insert(config.namespace.clone(), Running { config, task });
+        Ok(())
+    }
+
+    // Stopping the ingest ends the current group of every track,
+    // so the subscriptions end once the remaining objects were sent.
+    // Waits for the task to end, which unregisters the broadcast, so it can be started again.
+    async fn stop(&mut self, namespace: &str) {
+        if let Some(running) = self.running.remove(namespace) {
+            running.task.abort();
+            running.task.await.ok();
+            log::info!("stopped broadcast {}", namespace);
+        }
+    }
+
+    pub async fn handle(&mut self, command: Command) {
+        match command {
+            Command::Start(namespace, reply) => {
+                let config = self
+                    .configs
+                    .iter()
+                    .find(|config| config.namespace == namespace)
+                    .cloned();
+                let res = match config {
+                    _ if self.running.contains_key(&namespace) => {
+                        Err(anyhow::anyhow!("Broadcast already running"))
+                    }
+                    Some(config) => self.start(config).await,
+                    None => Err(not_configured()),
+                };
+                reply.send(res).ok();
+            }
+            Command::Stop(namespace, reply) => {
+                let configured = self
+                    .configs
+                    .iter()
+                    .any(|config| config.namespace == namespace);
+                let res = match self.running.contains_key(&namespace) {
+                    true => {
+                        self.stop(&namespace).await;
+                        Ok(())
+                    }
+                    false if configured => Err(anyhow::anyhow!("Broadcast not running")),
+                    false => Err(not_configured()),
+                };
+                reply.send(res).ok();
+            }
+        }
+    }
+
+    pub async fn stop_all(&mut self) {
+        let namespaces: Vec<String> = self.running.keys().cloned().collect();
+        for namespace in namespaces {
+            self.stop(&namespace).await;
+        }
+    }
+}
+
+// Starts or stops a configured broadcast, sent by the admin API.
+pub enum Command {
+    Start(String, oneshot::Sender<anyhow::Result<()>>),
+    Stop(String, oneshot::Sender<anyhow::Result<()>>),
+}
+
+fn not_configured() -> anyhow::Error {
+    RequestError::NotFound("Broadcast not configured".to_string()).into()
+}
+
+// ingests a broadcast with its mapping
+async fn run(config: BroadcastConfig, tracks: TracksWriter) -> anyhow::Result<()> {
+    let broadcast = Broadcast::new(tracks);
+    let input = config.input.clone();
+    let buffer = config.buffer();
+    let renditions = &config.renditions;
+
+    match config.mapping {
+        Mapping::Track => {
+            ingest::run(broadcast, input, buffer, renditions, StreamPerTrack::new).await
+        }
+        Mapping::Gop => ingest::run(broadcast, input, buffer, renditions, StreamPerGop::new).await,
+        Mapping::FrameType => {
+            ingest::run(
+                broadcast,
+                input,
+                buffer,
+                renditions,
+                StreamPerFrameType::new,
+            )
+            .await
+        }
+        Mapping::BFrame => {
+            ingest::run(broadcast, input, buffer, renditions, StreamPerBFrame::new).await
+        }
+        Mapping::Frame => {
+            ingest::run(broadcast, input, buffer, renditions, StreamPerFrame::new).await
+        }
+        Mapping::Datagram => {
+            ingest::run(broadcast, input, buffer, renditions, |rendition| {
+                let mut video = DatagramPerBFrame::new(rendition)?;
+                if let Some(threshold) = config.datagram_p_frame_size {
+                    video = video.with_p_frame_threshold(threshold);
+                }
+                Ok(video)
+            })
+            .await
+        }
+        Mapping::Temporal => {
+            ingest::run(
+                broadcast,
+                input,
+                buffer,
+                renditions,
+                TrackPerTemporalLayer::new,
+            )
+            .await
+        }
+        Mapping::MiniGop => {
+            ingest::run(broadcast, input, buffer, renditions, StreamPerMiniGop::new).await
+        }
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use tokio::sync::Notify;
+
+use crate::video::{FrameType, DISCONTINUITY_FLAG};
+
+// frames released this late restart the clock, so a stalled input adds at most this much
+// latency to the following frames
+const MAX_LATENESS: Duration = Duration::from_millis(100);
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+// What to do when the input produces media faster than real time and the buffer is full.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverloadPolicy {
+    // stop reading the input until there is space, so the encoder is slowed down
+    #[default]
+    Block,
+    // drop the oldest B-frames, or if there are none the oldest GOP
+    DropOldest,
+    // fail the input, which is then reopened and resynchronized
+    Error,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BufferConfig {
+    // items with a larger size field are treated as corrupt
+    pub max_item_size: usize,
+    // bytes buffered per input
+    pub capacity: usize,
+    pub policy: OverloadPolicy,
+    // release frames at the pace of their decode times, for inputs faster than real time
+    pub pacing: bool,
+}
+
+pub struct Buffered {
+    // the rendition of the item
+    pub index: usize,
+    pub item: BytesMut,
+    // the (rebased) decode time of a frame, None for init segments
+    pub media_time: Option<Duration>,
+}
+
+impl Buffered {
+    fn is_frame(&self) -> bool {
+        self.item[0] == 0x01
+    }
+
+    fn is_keyframe(&self) -> bool {
+        self.is_frame() && self.item[1] != 0
+    }
+
+    // no other frames depend on B-frames
+    fn is_b_frame(&self) -> bool {
+        self.is_frame() && self.item[2] & !DISCONTINUITY_FLAG == FrameType::B as u8
+    }
+}
+
+#[derive(Default)]
+struct State {
+    items: VecDeque<Buffered>,
+    bytes: usize,
+    max_bytes: usize,
+    dropped: u64,
+    closed: bool,
+}
+
+// The items of an input waiting to be released to the mappings.
+pub struct IngestBuffer {
+    config: BufferConfig,
+    state: Mutex<State>,
+    pushed: Notify,
+    popped: Notify,
+}
+
+impl IngestBuffer {
+    pub fn new(config: BufferConfig) -> Self {
+        Self {
+            config,
+            state: Default::default(),
+            pushed: Notify::new(),
+            popped: Notify::new(),
+        }
+    }
+
+    pub fn config(&self) -> BufferConfig {
+        self.config
+    }
+
+    pub async fn push(&self, buffered: Buffered) -> anyhow::Result<()> {
+        let size = buffered.item.len();
+        loop {
+            let popped = self.popped.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return Err(anyhow::anyhow!("Ingest buffer closed"));
+                }
+
+                if state.bytes + size > self.config.capacity && !state.items.is_empty() {
+                    match self.config.policy {
+                        OverloadPolicy::Error => {
+                            return Err(anyhow::anyhow!(
+                                "Ingest buffer full: {} bytes buffered",
+                                state.bytes
+                            ))
+                        }
+                        OverloadPolicy::DropOldest => drop_oldest(&mut state, size, self.config),
+                        OverloadPolicy::Block => {}
+                    }
+                }
+
+                // an item larger than the buffer is accepted once the buffer is empty
+                if state.bytes + size <= self.config.capacity || state.items.is_empty() {
+                    state.bytes += size;
+                    state.max_bytes = state.max_bytes.max(state.bytes);
+                    state.items.push_back(buffered);
+                    drop(state);
+                    self.pushed.notify_one();
+                    return Ok(());
+                }
+            }
+            popped.await;
+        }
+    }
+
+    // returns None once the buffer was closed and all items were popped
+    pub async fn pop(&self) -> Option<Buffered> {
+        loop {
+            let pushed = self.pushed.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(buffered) = state.items.pop_front() {
+                    state.bytes -= buffered.item.len();
+                    drop(state);
+                    self.popped.notify_one();
+                    return Some(buffered);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            pushed.await;
+        }
+    }
+
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.pushed.notify_one();
+        self.popped.notify_one();
+    }
+
+    pub fn log(&self, input: &str) {
+        let state = self.state.lock().unwrap();
+        log::info!(
+            target: "ingest",
+            "input={} buffered_items={} buffered_bytes={} max_buffered_bytes={} capacity={} dropped={}",
+            input,
+            state.items.len(),
+            state.bytes,
+            state.max_bytes,
+            self.config.capacity,
+            state.dropped,
+        );
+    }
+}
+
+// Drops B-frames, oldest first, and if there are none the oldest GOP, so the remaining frames
+// stay decodable. The oldest GOP may have been partially released already, then its remaining
+// frames are dropped. GOPs that aren't followed by a keyframe yet are kept.
+fn drop_oldest(state: &mut State, size: usize, config: BufferConfig) {
+    while state.bytes + size > config.capacity {
+        let dropped = match state.items.iter().position(Buffered::is_b_frame) {
+            Some(position) => vec![position],
+            None => match oldest_gop(&state.items) {
+                Some(positions) => positions,
+                None => return,
+            },
+        };
+
+        let first = dropped[0];
+        let index = state.items[first].index;
+        let discontinuity = dropped
+            .iter()
+            .any(|position| state.items[*position].item[2] & DISCONTINUITY_FLAG != 0);
+        for position in dropped.into_iter().rev() {
+            let dropped = state.items.remove(position).unwrap();
+            state.bytes -= dropped.item.len();
+            state.dropped += 1;
+        }
+
+        // the rendition's next keyframe starts after the discontinuity instead
+        if discontinuity {
+            let next = state
+                .items
+                .range_mut(first..)
+                .find(|buffered| buffered.index == index && buffered.is_keyframe());
+            if let Some(next) = next {
+                next.item[2] |= DISCONTINUITY_FLAG;
+            }
+        }
+    }
+}
+
+// the positions of the frames of the oldest GOP, up to the next keyframe of its rendition
+fn oldest_gop(items: &VecDeque<Buffered>) -> Option<Vec<usize>> {
+    let first = items.iter().position(Buffered::is_frame)?;
+    let index = items[first].index;
+    let mut positions = vec![first];
+    for (position, buffered) in items.iter().enumerate().skip(first + 1) {
+        if buffered.index != index || !buffered.is_frame() {
+            continue;
+        }
+        if buffered.is_keyframe() {
+            return Some(positions);
+        }
+        positions.push(position);
+    }
+    None
+}
+
+// Releases the frames of each rendition at the pace of their decode times, if pacing is enabled.
+pub struct Pacer {
+    enabled: bool,
+    // per rendition, when a frame was released and its media time
+    clocks: Vec<Option<(Instant, Duration)>>,
+    logged: Instant,
+}
+
+impl Pacer {
+    pub fn new(renditions: usize, enabled: bool) -> Self {
+        Self {
+            enabled,
+            clocks: vec![None; renditions],
+            logged: Instant::now(),
+        }
+    }
+
+    pub async fn wait(&mut self, buffered: &Buffered) {
+        if !self.enabled {
+            return;
+        }
+        let Some(media_time) = buffered.media_time else {
+            return;
+        };
+        let Some(clock) = self.clocks.get_mut(buffered.index) else {
+            return;
+        };
+
+        let now = Instant::now();
+        let release = match *clock {
+            Some((started, start)) if media_time >= start => started + (media_time - start),
+            _ => now,
+        };
+        if release + MAX_LATENESS < now {
+            *clock = None;
+        }
+        match clock {
+            Some(_) => tokio::time::sleep_until(release.into()).await,
+            None => *clock = Some((now, media_time)),
+        }
+    }
+
+    // whether the buffer metrics are due to be logged
+    pub fn log_due(&mut self) -> bool {
+        if self.logged.elapsed() < LOG_INTERVAL {
+            return false;
+        }
+        self.logged = Instant::now();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use FrameType::{B, I, P};
+
+    const CONFIG: BufferConfig = BufferConfig {
+        max_item_size: 1024,
+        capacity: 100,
+        policy: OverloadPolicy::DropOldest,
+        pacing: false,
+    };
+
+    // a frame item of 10 bytes
+    fn frame(index: usize, frame_type: FrameType) -> Buffered {
+        let mut item = BytesMut::from(&[0u8; 10][..]);
+        item[0] = 0x01;
+        item[1] = (frame_type == FrameType::I) as u8;
+        item[2] = frame_type as u8;
+        Buffered {
+            index,
+            item,
+            media_time: None,
+        }
+    }
+
+    fn state(frames: &[(usize, FrameType)]) -> State {
+        let items: VecDeque<Buffered> = frames
+            .iter()
+            .map(|(index, frame_type)| frame(*index, *frame_type))
+            .collect();
+        State {
+            bytes: items.len() * 10,
+            items,
+            ..Default::default()
+        }
+    }
+
+    fn frames(state: &State) -> Vec<(usize, FrameType)> {
+        state
+            .items
+            .iter()
+            .map(|buffered| {
+                let frame_type = FrameType::try_from(buffered.item[2] & !DISCONTINUITY_FLAG);
+                (buffered.index, frame_type.unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drops_one_whole_gop() {
+        let mut state = state(&[(0, I), (0, P), (0, P), (0, I), (0, P), (0, I), (0, P)]);
+        // room for one more frame
+        drop_oldest(&mut state, 40, CONFIG);
+        assert_eq!(frames(&state), vec![(0, I), (0, P), (0, I), (0, P)]);
+        assert_eq!(state.bytes, 40);
+        assert_eq!(state.dropped, 3);
+    }
+
+    #[test]
+    fn drops_b_frames_before_gops() {
+        let mut state = state(&[(0, I), (0, B), (0, P), (0, B), (0, I), (0, P)]);
+        drop_oldest(&mut state, 60, CONFIG);
+        assert_eq!(frames(&state), vec![(0, I), (0, P), (0, I), (0, P)]);
+        assert_eq!(state.dropped, 2);
+    }
+
+    #[test]
+    fn keeps_gop_in_progress() {
+        let mut state = state(&[(0, I), (0, P), (0, I), (0, P), (0, P)]);
+        // even if that isn't enough room
+        drop_oldest(&mut state, 90, CONFIG);
+        assert_eq!(frames(&state), vec![(0, I), (0, P), (0, P)]);
+        assert_eq!(state.dropped, 2);
+    }
+
+    #[test]
+    fn keeps_single_gop() {
+        let mut state = state(&[(0, I), (0, P), (0, P)]);
+        drop_oldest(&mut state, 90, CONFIG);
+        assert_eq!(frames(&state), vec![(0, I), (0, P), (0, P)]);
+        assert_eq!(state.dropped, 0);
+    }
+
+    #[test]
+    fn drops_gop_of_one_rendition() {
+        let mut state = state(&[(0, I), (1, I), (0, P), (1, P), (0, I), (1, I)]);
+        drop_oldest(&mut state, 60, CONFIG);
+        assert_eq!(frames(&state), vec![(1, I), (1, P), (0, I), (1, I)]);
+        assert_eq!(state.dropped, 2);
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+use moq_transport::serve::{TrackReader, TrackReaderMode};
+use tokio::sync::watch;
+
+use crate::video::{parse_frame_header, FrameType};
+
+// Where a new subscriber starts.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum JoinMode {
+    // at the most recent keyframe, so the first frame sent is decodable
+    #[default]
+    Keyframe,
+    // at the newest frame; frames up to the next keyframe are marked as undecodable
+    LiveEdge,
+}
+
+// The mode of the cached track, so a subscriber's copy can be written the same way.
+#[derive(Clone, Copy, Debug)]
+pub enum CacheMode {
+    Stream,
+    Groups,
+    Objects,
+}
+
+#[derive(Clone)]
+pub struct CachedObject {
+    pub group_id: u64,
+    pub object_id: u64,
+    pub priority: u64,
+    pub payload: Bytes,
+    // the first object of a GOP, i.e. a keyframe or, on tracks without keyframes
+    // (e.g. b-frames or enhancement layers), the first object of a group
+    pub gop_start: bool,
+    // when the object was cached
+    pub cached: SystemTime,
+}
+
+struct State {
+    // sequence number of the first cached object
+    first: u64,
+    objects: Vec<CachedObject>,
+    closed: bool,
+    // incremented to make the subscribers' copies start a new group at the next object
+    splits: u64,
+}
+
+// Caches the objects of a track since the start of the current GOP, so new subscribers
+// can start at the most recent keyframe no matter how the mapping groups the frames.
+#[derive(Clone)]
+pub struct GopCache {
+    pub mode: CacheMode,
+    state: Arc<Mutex<State>>,
+    notify: Arc<watch::Sender<()>>,
+}
+
+impl GopCache {
+    fn new(mode: CacheMode) -> Self {
+        Self {
+            mode,
+            state: Arc::new(Mutex::new(State {
+                first: 0,
+                objects: Vec::new(),
+                closed: false,
+                splits: 0,
+            })),
+            notify: Arc::new(watch::channel(()).0),
+        }
+    }
+
+    fn push(&self, object: CachedObject) {
+        let mut state = self.state.lock().unwrap();
+        if object.gop_start {
+            state.first += state.objects.len() as u64;
+            state.objects.clear();
+        }
+        state.objects.push(object);
+        drop(state);
+        self.notify.send_replace(());
+    }
+
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.notify.send_replace(());
+    }
+
+    // whether both are the same cache
+    pub fn same(&self, other: &GopCache) -> bool {
+        Arc::ptr_eq(&self.state, &other.state)
+    }
+
+    // the group and object id of the newest cached object
+    pub fn latest(&self) -> Option<(u64, u64)> {
+        let state = self.state.lock().unwrap();
+        state
+            .objects
+            .last()
+            .map(|object| (object.group_id, object.object_id))
+    }
+
+    // Makes the subscribers' copies of the track start a new group at the next GOP, so the
+    // new group can be decoded on its own. A keyframe should be requested to start it soon.
+    // Objects are sent with their original ids, so this only works for streams and groups.
+    pub fn split(&self) -> anyhow::Result<()> {
+        if let CacheMode::Objects = self.mode {
+            return Err(anyhow::anyhow!("Track isn't sent in groups"));
+        }
+        self.state.lock().unwrap().splits += 1;
+        Ok(())
+    }
+
+    pub fn reader(&self, join: JoinMode) -> GopCacheReader {
+        let state = self.state.lock().unwrap();
+        let seq = match join {
+            JoinMode::Keyframe => state.first,
+            JoinMode::LiveEdge => state.first + state.objects.len().saturating_sub(1) as u64,
+        };
+        GopCacheReader {
+            cache: self.clone(),
+            seq,
+            notify: self.notify.subscribe(),
+            splits: state.splits,
+            split: false,
+        }
+    }
+
+    // Fills a cache from a local track until the track ends.
+    // Returns None for tracks that can't be cached, i.e. datagram tracks.
+    pub async fn tap(track: TrackReader) -> anyhow::Result<Option<(Self, GopTap)>> {
+        let source = track.mode().await?;
+        let mode = match &source {
+            TrackReaderMode::Stream(_) => CacheMode::Stream,
+            TrackReaderMode::Groups(_) => CacheMode::Groups,
+            TrackReaderMode::Objects(_) => CacheMode::Objects,
+            TrackReaderMode::Datagrams(_) => return Ok(None),
+        };
+        let cache = Self::new(mode);
+        let tap = GopTap {
+            source,
+            filler: Filler {
+                cache: cache.clone(),
+                keyframes: false,
+                group_id: None,
+            },
+        };
+        Ok(Some((cache, tap)))
+    }
+}
+
+pub struct GopTap {
+    source: TrackReaderMode,
+    filler: Filler,
+}
+
+impl GopTap {
+    pub async fn run(self) -> anyhow::Result<()> {
+        let Self { source, mut filler } = self;
+        let res = filler.read(source).await;
+        filler.cache.close();
+        res
+    }
+}
+
+struct Filler {
+    cache: GopCache,
+    // whether the track carries keyframes, otherwise GOPs are delimited by groups
+    keyframes: bool,
+    group_id: Option<u64>,
+}
+
+impl Filler {
+    async fn read(&mut self, source: TrackReaderMode) -> anyhow::Result<()> {
+        match source {
+            TrackReaderMode::Stream(mut stream) => {
+                while let Some(mut group) = stream.next().await? {
+                    let mut object_id = 0;
+                    while let Some(payload) = group.read_next().await? {
+                        self.push(group.group_id, object_id, 0, payload);
+                        object_id += 1;
+                    }
+                }
+            }
+            TrackReaderMode::Groups(mut groups) => {
+                while let Some(mut group) = groups.next().await? {
+                    let mut object_id = 0;
+                    while let Some(payload) = group.read_next().await? {
+                        self.push(group.group_id, object_id, group.priority, payload);
+                        object_id += 1;
+                    }
+                }
+            }
+            TrackReaderMode::Objects(mut objects) => {
+                while let Some(mut object) = objects.next().await? {
+                    let payload = object.read_all().await?;
+                    self.push(object.group_id, object.object_id, object.priority, payload);
+                }
+            }
+            TrackReaderMode::Datagrams(_) => {}
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, group_id: u64, object_id: u64, priority: u64, payload: Bytes) {
+        let keyframe = matches!(
+            parse_frame_header(&payload),
+            Ok(header) if header.frame_type == FrameType::I
+        );
+        self.keyframes |= keyframe;
+
+        let new_group = self.group_id.is_some_and(|id| id != group_id);
+        self.group_id = Some(group_id);
+        let gop_start = keyframe || (!self.keyframes && new_group);
+
+        // the tap may have started in the middle of a GOP
+        let empty = self.cache.state.lock().unwrap().objects.is_empty();
+        if empty && !gop_start {
+            return;
+        }
+
+        self.cache.push(CachedObject {
+            group_id,
+            object_id,
+            priority,
+            payload,
+            gop_start,
+            cached: SystemTime::now(),
+        });
+    }
+}
+
+// Reads the objects of a cache, starting at the join position.
+// A reader that falls more than a GOP behind continues at the most recent keyframe.
+pub struct GopCacheReader {
+    cache: GopCache,
+    seq: u64,
+    notify: watch::Receiver<()>,
+    splits: u64,
+    // whether a new group was requested and hasn't been started yet
+    split: bool,
+}
+
+impl GopCacheReader {
+    // whether a new group should be started, called at the start of a GOP
+    pub fn take_split(&mut self) -> bool {
+        std::mem::take(&mut self.split)
+    }
+
+    pub async fn next(&mut self) -> anyhow::Result<Option<CachedObject>> {
+        loop {
+            self.notify.borrow_and_update();
+            {
+                let state = self.cache.state.lock().unwrap();
+                if self.seq < state.first {
+                    log::debug!("skipping {} cached objects", state.first - self.seq);
+                    self.seq = state.first;
+                }
+                if state.splits != self.splits {
+                    self.splits = state.splits;
+                    self.split = true;
+                }
+                if let Some(object) = state.objects.get((self.seq - state.first) as usize) {
+                    self.seq += 1;
+                    return Ok(Some(object.clone()));
+                }
+                if state.closed {
+                    return Ok(None);
+                }
+            }
+            if self.notify.changed().await.is_err() {
+                return Ok(None);
+            }
+        }
+    }
+}
@@ -0,0 +1,485 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::Parser;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    buffer::{BufferConfig, OverloadPolicy},
+    cache::JoinMode,
+    deadline::Deadline,
+    forward::PriorityPolicy,
+    ingest::{Input, RenditionArgs},
+    mappings::Mapping,
+    sendlog::SendLogFormat,
+    server::ForwardConfig,
+};
+
+// The configuration file, e.g.
+//
+//   [[listener]]
+//   bind = "[::]:443"
+//
+//   [tls]
+//   cert = ["cert.pem"]
+//   key = ["key.pem"]
+//
+//   [auth]
+//   allow = ["10.0.0.0/8", "::1"]
+//
+//   [limits]
+//   max_sessions = 100
+//   max_subscriptions = 20
+//   max_connections_per_ip = 10
+//   idle_timeout = 30000
+//
+//   [egress]
+//   session_rate = 8000
+//   global_rate = 1000000
+//
+//   [impairment]
+//   delay = 50
+//   loss = 0.01
+//   trace = "testbed/network-patterns/twitch-challenge/normal/PROFILE_SPIKE.json"
+//   trace_loop = true
+//   bandwidth_log = "bandwidth.csv"
+//
+//   [admin]
+//   bind = "127.0.0.1:8080"
+//
+//   [logging]
+//   qlog_dir = "qlog"
+//   send_log_dir = "sends"
+//   send_log_format = "jsonl"
+//
+//   [[broadcast]]
+//   namespace = "livestream"
+//   input = "/tmp/livestream.fifo"
+//   mapping = "frame-type"
+//   drop_budget = 200
+//   priority = "newest-first"
+//
+// Broadcasts are reloaded on SIGHUP, the other sections only on restart.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "listener")]
+    pub listeners: Vec<ListenerConfig>,
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub egress: EgressConfig,
+    #[serde(default)]
+    pub impairment: ImpairConfig,
+    pub admin: Option<AdminConfig>,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+    #[serde(default, rename = "broadcast")]
+    pub broadcasts: Vec<BroadcastConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let config: Self =
+            toml::from_str(&data).with_context(|| format!("failed to parse {}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.listeners.is_empty() {
+            anyhow::bail!("missing listener");
+        }
+        let mut namespaces = HashSet::new();
+        for broadcast in &self.broadcasts {
+            if !namespaces.insert(&broadcast.namespace) {
+                anyhow::bail!("duplicate broadcast {}", broadcast.namespace);
+            }
+        }
+        self.impairment.validate()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub bind: SocketAddr,
+}
+
+// the admin API, see admin.rs
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    pub bind: SocketAddr,
+}
+
+// Traces written for offline analysis.
+#[derive(clap::Args, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// Write a qlog trace per connection to this directory, named by the connection id
+    #[arg(long)]
+    pub qlog_dir: Option<PathBuf>,
+    /// Log the frames sent to (or dropped for) each subscriber to this directory
+    #[arg(long)]
+    pub send_log_dir: Option<PathBuf>,
+    /// The format of the send logs
+    #[arg(long, value_enum, default_value_t = SendLogFormat::Csv)]
+    pub send_log_format: SendLogFormat,
+}
+
+impl LoggingConfig {
+    // creates the directories of the enabled traces
+    pub fn create_dirs(&self) -> anyhow::Result<()> {
+        for dir in [&self.qlog_dir, &self.send_log_dir].into_iter().flatten() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Vec<PathBuf>,
+    pub key: Vec<PathBuf>,
+}
+
+// the TLS arguments of moq-native, so the configuration is loaded the same way as from the CLI
+#[derive(Parser)]
+struct TlsArgs {
+    #[command(flatten)]
+    tls: moq_native::tls::Args,
+}
+
+impl TlsConfig {
+    pub fn load(&self) -> anyhow::Result<moq_native::tls::Config> {
+        let mut args = vec!["tls".into()];
+        for cert in &self.cert {
+            args.push("--tls-cert".into());
+            args.push(cert.clone().into_os_string());
+        }
+        for key in &self.key {
+            args.push("--tls-key".into());
+            args.push(key.clone().into_os_string());
+        }
+        TlsArgs::try_parse_from(args)?.tls.load()
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    // the addresses (or networks, e.g. 10.0.0.0/8) clients may connect from, any if empty
+    #[serde(default)]
+    pub allow: Vec<Network>,
+}
+
+impl AuthConfig {
+    pub fn allows(&self, addr: IpAddr) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|network| network.contains(addr))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().context("invalid address")?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().context("invalid prefix length")?,
+            None => max,
+        };
+        if prefix > max {
+            anyhow::bail!("invalid prefix length {}", prefix);
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+// Limits on the sessions and their subscriptions, see limits.rs.
+// Also used for the command line.
+#[derive(clap::Args, Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Maximum number of concurrent sessions
+    #[arg(long)]
+    pub max_sessions: Option<usize>,
+    /// Maximum number of concurrent subscriptions per session
+    #[arg(long)]
+    pub max_subscriptions: Option<usize>,
+    /// Maximum number of connections per minute from the same IP address
+    #[arg(long)]
+    pub max_connections_per_ip: Option<u32>,
+    /// Close connections that don't complete the QUIC and WebTransport handshake, or the
+    /// MoQ setup after it, within this many milliseconds
+    #[arg(long, default_value_t = 10000)]
+    pub handshake_timeout: u64,
+    /// Maximum number of handshakes in progress, further connections are ignored
+    #[arg(long, default_value_t = 100)]
+    pub max_handshakes: usize,
+    /// Close sessions without subscriptions for this many milliseconds
+    #[arg(long)]
+    pub idle_timeout: Option<u64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_sessions: None,
+            max_subscriptions: None,
+            max_connections_per_ip: None,
+            handshake_timeout: 10000,
+            max_handshakes: 100,
+            idle_timeout: None,
+        }
+    }
+}
+
+// Network impairments emulated by the server, see impair.rs. Also used for the command line.
+// Clients connect through a relay, so this can't be combined with auth or per-IP limits.
+#[derive(clap::Args, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImpairConfig {
+    /// Delay added to the packets in each direction, in milliseconds
+    #[arg(long = "impair-delay", default_value_t = 0)]
+    pub delay: u64,
+    /// Random variation of the delay, up to this many milliseconds either way
+    #[arg(long = "impair-jitter", default_value_t = 0)]
+    pub jitter: u64,
+    /// Fraction of the packets lost in each direction, e.g. 0.01
+    #[arg(long = "impair-loss", default_value_t = 0.0)]
+    pub loss: f64,
+    /// Bandwidth from the server to the clients, in kbit/s
+    #[arg(long = "impair-bandwidth")]
+    pub bandwidth: Option<u64>,
+    /// Replay a bandwidth trace from the server to the clients instead, given as a JSON list
+    /// of {"speed": KBPS, "duration": SECONDS} (see testbed/network-patterns)
+    #[arg(long = "impair-trace")]
+    pub trace: Option<PathBuf>,
+    /// Replay the bandwidth trace in a loop instead of lifting the limit once it ended
+    #[arg(long = "impair-trace-loop")]
+    pub trace_loop: bool,
+    /// Log the bandwidth applied over time to this CSV file, requires an impairment
+    #[arg(long = "impair-bandwidth-log")]
+    pub bandwidth_log: Option<PathBuf>,
+    /// Packets queued by the bandwidth limit before they are dropped
+    #[arg(long = "impair-queue", default_value_t = 50)]
+    pub queue: usize,
+    /// Seed of the random losses and jitter, so experiments can be reproduced
+    #[arg(long = "impair-seed")]
+    pub seed: Option<u64>,
+}
+
+impl Default for ImpairConfig {
+    fn default() -> Self {
+        Self {
+            delay: 0,
+            jitter: 0,
+            loss: 0.0,
+            bandwidth: None,
+            trace: None,
+            trace_loop: false,
+            bandwidth_log: None,
+            queue: 50,
+            seed: None,
+        }
+    }
+}
+
+impl ImpairConfig {
+    pub fn enabled(&self) -> bool {
+        self.delay > 0
+            || self.jitter > 0
+            || self.loss > 0.0
+            || self.bandwidth.is_some()
+            || self.trace.is_some()
+    }
+
+    // NaN isn't in the range either
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0.0..=1.0).contains(&self.loss) {
+            anyhow::bail!("impairment loss {} must be between 0 and 1", self.loss);
+        }
+        Ok(())
+    }
+}
+
+// Egress rate limits, see egress.rs. Also used for the command line.
+#[derive(clap::Args, Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct EgressConfig {
+    /// Limit the media sent to each session, in kbit/s
+    #[arg(long = "egress-session-rate")]
+    pub session_rate: Option<u64>,
+    /// Limit the media sent to all sessions, in kbit/s
+    #[arg(long = "egress-global-rate")]
+    pub global_rate: Option<u64>,
+    /// The burst allowed by the egress limits, in milliseconds at the limited rate
+    #[arg(long = "egress-burst", default_value_t = 100)]
+    pub burst: u64,
+    /// Drop frames delayed longer than this many milliseconds by the egress limits,
+    /// the first frame of a GOP is only delayed
+    #[arg(long = "egress-max-delay", default_value_t = 500)]
+    pub max_delay: u64,
+}
+
+impl Default for EgressConfig {
+    fn default() -> Self {
+        Self {
+            session_rate: None,
+            global_rate: None,
+            burst: 100,
+            max_delay: 500,
+        }
+    }
+}
+
+// A broadcast: where its media is read from and how it is mapped and served.
+// Also used for the command line, which describes a single broadcast.
+#[derive(clap::Args, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BroadcastConfig {
+    /// The namespace of the broadcast
+    #[arg(long, default_value = "livestream")]
+    pub namespace: String,
+    /// How the video is mapped to tracks, groups and objects
+    #[arg(long, value_enum, default_value_t = Mapping::Track)]
+    pub mapping: Mapping,
+    /// Drop frames that can't arrive within this many milliseconds of their presentation time,
+    /// while they wait on the server; frames already being sent aren't cancelled
+    #[arg(long)]
+    pub frame_deadline: Option<u64>,
+    /// Drop frames when a subscriber's queue delay exceeds this many milliseconds
+    #[arg(long)]
+    pub drop_budget: Option<u64>,
+    /// Where new subscribers start: at the most recent keyframe, or at the newest frame
+    /// with the frames before the next keyframe marked as undecodable
+    #[arg(long, value_enum, default_value_t = JoinMode::Keyframe)]
+    pub join: JoinMode,
+    /// How the objects are prioritized when sent to subscribers: with the priorities of the
+    /// mapping, newer groups first, or all alike in order
+    #[arg(long, value_enum, default_value_t = PriorityPolicy::Mapping)]
+    pub priority: PriorityPolicy,
+    /// With the datagram mapping, also send P-frames up to this many bytes as datagrams
+    #[arg(long)]
+    pub datagram_p_frame_size: Option<usize>,
+    /// Read media from stdin (-), a file or FIFO, or a unix socket (unix:PATH).
+    /// Inputs are reopened when they end, e.g. when the encoder restarts.
+    #[arg(long, default_value = "-")]
+    pub input: Input,
+    /// Inputs with larger items are treated as corrupt, in bytes
+    #[arg(long, default_value_t = 16 << 20)]
+    pub max_item_size: usize,
+    /// Media of an input waiting to be released to the mappings, in bytes
+    #[arg(long, default_value_t = 64 << 20)]
+    pub ingest_buffer: usize,
+    /// What to do when an input produces media faster than it is released and its buffer is full
+    #[arg(long, value_enum, default_value_t = OverloadPolicy::Block)]
+    pub ingest_overload: OverloadPolicy,
+    /// Release the media of an input at the pace of its decode times, for inputs that are
+    /// read faster than real time, like files
+    #[arg(long)]
+    pub ingest_pacing: bool,
+    /// Ingest a rendition, given as NAME[@KBPS][=INPUT] (repeatable).
+    /// Renditions without an input are read interleaved from the input.
+    #[arg(long = "rendition")]
+    pub renditions: Vec<RenditionArgs>,
+    /// Forward keyframe requests to the encoder through this FIFO or unix socket
+    #[arg(long)]
+    pub keyframe_requests: Option<PathBuf>,
+    /// Minimum time between two keyframe requests for the same rendition, in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    pub keyframe_request_interval: u64,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        Self {
+            namespace: "livestream".to_string(),
+            mapping: Mapping::Track,
+            frame_deadline: None,
+            drop_budget: None,
+            join: JoinMode::Keyframe,
+            priority: PriorityPolicy::Mapping,
+            datagram_p_frame_size: None,
+            input: Input::Stdin,
+            max_item_size: 16 << 20,
+            ingest_buffer: 64 << 20,
+            ingest_overload: OverloadPolicy::Block,
+            ingest_pacing: false,
+            renditions: Vec::new(),
+            keyframe_requests: None,
+            keyframe_request_interval: 1000,
+        }
+    }
+}
+
+impl BroadcastConfig {
+    pub fn forward(&self) -> ForwardConfig {
+        ForwardConfig {
+            deadline: self
+                .frame_deadline
+                .map(|ms| Deadline::new(Duration::from_millis(ms))),
+            drop_budget: self.drop_budget.map(Duration::from_millis),
+            join: self.join,
+            priority: self.priority,
+        }
+    }
+
+    pub fn buffer(&self) -> BufferConfig {
+        BufferConfig {
+            max_item_size: self.max_item_size,
+            capacity: self.ingest_buffer,
+            policy: self.ingest_overload,
+            pacing: self.ingest_pacing,
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use crate::{
+    keyframe::KeyframeRequests,
+    stats::SessionStats,
+    video::{parse_frame_header, FrameType},
+};
+
+// Drops frames of a subscriber's track when the session's queue delay exceeds a budget,
+// following the dependencies between the frames:
+// - above the budget, B-frames are dropped
+// - above twice the budget, P-frames are dropped as well, and since the following frames
+//   of the GOP depend on them, everything is dropped until the next keyframe,
+//   which is requested from the encoder if keyframe requests are enabled
+pub struct FrameDropper {
+    budget: Duration,
+    stats: SessionStats,
+    skip_to_keyframe: bool,
+    dropped: u64,
+
+    // requests keyframes for the track's rendition
+    keyframes: Option<KeyframeRequests>,
+}
+
+impl FrameDropper {
+    pub fn new(budget: Duration, stats: SessionStats, keyframes: Option<KeyframeRequests>) -> Self {
+        Self {
+            budget,
+            stats,
+            skip_to_keyframe: false,
+            dropped: 0,
+            keyframes,
+        }
+    }
+
+    pub fn forward(&mut self, frame: &[u8]) -> anyhow::Result<bool> {
+        let header = parse_frame_header(frame)?;
+        if header.frame_type == FrameType::I {
+            self.skip_to_keyframe = false;
+            return Ok(true);
+        }
+
+        let delay = self.stats.queue_delay();
+        let forward = match header.frame_type {
+            _ if self.skip_to_keyframe => false,
+            FrameType::P if delay > 2 * self.budget => {
+                self.skip_to_keyframe = true;
+                log::info!(
+                    "skipping to next keyframe: conn={} queue_delay={:?}",
+                    self.stats.id(),
+                    delay
+                );
+                if let Some(keyframes) = &self.keyframes {
+                    keyframes.request();
+                }
+                false
+            }
+            FrameType::B => delay <= self.budget,
+            _ => true,
+        };
+
+        if !forward {
+            self.dropped += 1;
+            log::debug!(
+                "dropping {:?}-frame: conn={} queue_delay={:?} dropped={}",
+                header.frame_type,
+                self.stats.id(),
+                delay,
+                self.dropped
+            );
+        }
+        Ok(forward)
+    }
+}
@@ -0,0 +1,257 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use moq_transport::serve::TracksReader;
+
+use crate::{
+    broadcast::read_first,
+    cache::CachedObject,
+    stats::SessionStats,
+    video::{parse_frame_header, parse_timescale},
+};
+
+// frames are handed to the session while less than this is queued in it,
+// the remaining ones wait in the filter where they can still be dropped
+const RELEASE_QUEUE_DELAY: Duration = Duration::from_millis(20);
+// the shortest wait between two checks, when the estimates are right at a limit
+const MIN_WAIT: Duration = Duration::from_millis(1);
+
+#[derive(Clone, Copy)]
+pub struct Deadline {
+    // how long after its presentation time a frame may still arrive at the subscriber
+    pub budget: Duration,
+}
+
+impl Deadline {
+    pub fn new(budget: Duration) -> Self {
+        Self { budget }
+    }
+
+    // the deadline of a frame is its presentation time (relative to when it became available) plus the budget
+    fn deadline(&self, payload: &[u8], timescale: u32) -> anyhow::Result<SystemTime> {
+        let header = parse_frame_header(payload)?;
+        let offset = header.presentation_time.saturating_sub(header.decode_time);
+        let offset = Duration::from_secs_f64(offset as f64 / timescale as f64);
+        Ok(header.availability_time + offset + self.budget)
+    }
+
+    // a filter for the frames of a subscriber's track, see DeadlineFilter
+    pub async fn filter(
+        self,
+        stats: SessionStats,
+        tracks: TracksReader,
+        name: &str,
+    ) -> anyhow::Result<DeadlineFilter> {
+        Ok(DeadlineFilter {
+            deadline: self,
+            stats,
+            timescale: timescale(tracks, name).await?,
+            pending: Pending::default(),
+        })
+    }
+}
+
+// Holds the frames of a subscriber's track until the session can send them without queueing,
+// and drops the frames that can no longer arrive before their deadline while they wait.
+// Frames are only held here: once handed to the session, a late frame is sent completely.
+// moq-transport doesn't reset the stream of an object closed with an error, it finishes it
+// as if the (truncated) frame was complete, so a frame can't be cancelled once handed off.
+// Keeping the session's queue short keeps the frames that can't be cancelled few.
+pub struct DeadlineFilter {
+    deadline: Deadline,
+    stats: SessionStats,
+    timescale: u32,
+    pending: Pending,
+}
+
+pub enum Released {
+    Forward(CachedObject),
+    Expired(CachedObject),
+}
+
+impl DeadlineFilter {
+    pub fn push(&mut self, object: CachedObject) -> anyhow::Result<()> {
+        let deadline = self.deadline.deadline(&object.payload, self.timescale)?;
+        self.pending.frames.push((deadline, object));
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.frames.is_empty()
+    }
+
+    // Waits until a frame expired or the session's queue is short enough to hand it a frame,
+    // the most important frame first.
+    pub async fn release(&mut self) -> Released {
+        loop {
+            let queue_delay = self.stats.queue_delay();
+            let rtt = self.stats.get().rtt;
+            let wait = match self.pending.next(SystemTime::now(), queue_delay, rtt) {
+                Ok(Released::Expired(object)) => {
+                    log::debug!(
+                        "dropping expired frame: conn={} dropped={}",
+                        self.stats.id(),
+                        self.pending.dropped
+                    );
+                    return Released::Expired(object);
+                }
+                Ok(released) => return released,
+                Err(wait) => wait,
+            };
+            tokio::time::sleep(wait.max(MIN_WAIT)).await;
+        }
+    }
+}
+
+// The frames waiting to be handed to the session, with their deadline.
+#[derive(Default)]
+struct Pending {
+    frames: Vec<(SystemTime, CachedObject)>,
+    dropped: u64,
+}
+
+impl Pending {
+    // The frame to release given the session's queue delay, otherwise the time until the
+    // queue is expected to be short enough or, if it stays as long, the next frame expires.
+    fn next(
+        &mut self,
+        now: SystemTime,
+        queue_delay: Duration,
+        rtt: Duration,
+    ) -> Result<Released, Duration> {
+        let arrival = now + queue_delay + rtt / 2;
+        let expired = self
+            .frames
+            .iter()
+            .position(|(deadline, _)| arrival > *deadline);
+        if let Some(index) = expired {
+            self.dropped += 1;
+            return Ok(Released::Expired(self.frames.remove(index).1));
+        }
+
+        if queue_delay <= RELEASE_QUEUE_DELAY {
+            let next = self
+                .frames
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, (_, object))| {
+                    let order = (object.group_id, object.object_id);
+                    (object.priority, std::cmp::Reverse(order))
+                })
+                .map(|(index, _)| index);
+            if let Some(index) = next {
+                return Ok(Released::Forward(self.frames.remove(index).1));
+            }
+        }
+
+        // the queue drains at the estimated capacity, i.e. its delay by a second per second
+        let drained = queue_delay.saturating_sub(RELEASE_QUEUE_DELAY);
+        let expiry = self
+            .frames
+            .iter()
+            .map(|(deadline, _)| deadline.duration_since(arrival).unwrap_or_default())
+            .min();
+        Err(expiry.map_or(drained, |expiry| expiry.min(drained)))
+    }
+}
+
+// reads the timescale from the init segment of the track's rendition
+async fn timescale(mut tracks: TracksReader, name: &str) -> anyhow::Result<u32> {
+    let init = match name.split_once('/') {
+        Some((_, rendition)) => format!("init/{}", rendition),
+        None => "init".to_string(),
+    };
+    let data = read_first(&mut tracks, &init).await?;
+    parse_timescale(&data).context("missing timescale in init segment")
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn object(group_id: u64, priority: u64) -> CachedObject {
+        CachedObject {
+            group_id,
+            object_id: 0,
+            priority,
+            payload: Bytes::new(),
+            gop_start: false,
+            cached: SystemTime::now(),
+        }
+    }
+
+    fn pending(now: SystemTime, frames: &[(u64, u64, u64)]) -> Pending {
+        let frames = frames
+            .iter()
+            .map(|(deadline, group_id, priority)| {
+                let deadline = now + Duration::from_millis(*deadline);
+                (deadline, object(*group_id, *priority))
+            })
+            .collect();
+        Pending { frames, dropped: 0 }
+    }
+
+    fn released(result: Result<Released, Duration>) -> (bool, u64) {
+        match result {
+            Ok(Released::Forward(object)) => (true, object.group_id),
+            Ok(Released::Expired(object)) => (false, object.group_id),
+            Err(wait) => panic!("waiting {:?}", wait),
+        }
+    }
+
+    const RTT: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn releases_most_important_frame_first() {
+        let now = SystemTime::now();
+        let mut pending = pending(now, &[(500, 1, 1), (500, 2, 3), (500, 3, 3)]);
+
+        // the same priority goes in order
+        assert_eq!(released(pending.next(now, Duration::ZERO, RTT)), (true, 2));
+        assert_eq!(released(pending.next(now, Duration::ZERO, RTT)), (true, 3));
+        assert_eq!(released(pending.next(now, Duration::ZERO, RTT)), (true, 1));
+    }
+
+    #[test]
+    fn drops_frames_that_would_arrive_late() {
+        let now = SystemTime::now();
+        let mut pending = pending(now, &[(100, 1, 5), (500, 2, 1)]);
+
+        // queued for 200ms, the first frame would arrive after its deadline
+        let queue_delay = Duration::from_millis(200);
+        assert_eq!(released(pending.next(now, queue_delay, RTT)), (false, 1));
+        assert_eq!(pending.dropped, 1);
+        assert!(pending.next(now, queue_delay, RTT).is_err());
+        assert_eq!(released(pending.next(now, Duration::ZERO, RTT)), (true, 2));
+    }
+
+    #[test]
+    fn waits_until_drained_or_expired() {
+        let now = SystemTime::now();
+        let mut pending = pending(now, &[(400, 1, 1)]);
+
+        // the queue is short enough in 80ms
+        let queue_delay = Duration::from_millis(100);
+        let wait = pending.next(now, queue_delay, RTT).err().unwrap();
+        assert_eq!(wait, Duration::from_millis(80));
+
+        // the frame expires in 50ms if the queue stays as long
+        let queue_delay = Duration::from_millis(340);
+        let wait = pending.next(now, queue_delay, RTT).err().unwrap();
+        assert_eq!(wait, Duration::from_millis(50));
+    }
+
+    #[test]
+    fn released_frames_are_not_cancelled() {
+        let now = SystemTime::now();
+        let mut pending = pending(now, &[(100, 1, 1)]);
+        assert_eq!(released(pending.next(now, Duration::ZERO, RTT)), (true, 1));
+
+        // a frame handed to the session is sent even if it becomes late
+        let later = now + Duration::from_millis(500);
+        assert!(pending.next(later, Duration::from_secs(1), RTT).is_err());
+        assert_eq!(pending.dropped, 0);
+    }
+}
@@ -0,0 +1,430 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use moq_transport::serve::{DatagramsReader, DatagramsWriter};
+use tokio::{sync::Notify, time::Instant};
+
+use crate::config::EgressConfig;
+
+// Limits the rate a bucket's tokens (bytes) can be spent at. A single object may exceed
+// the burst, it is sent once the bucket is full and leaves the bucket in debt.
+struct TokenBucket {
+    // bytes per second
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(kbps: u64, burst: Duration) -> Self {
+        let rate = kbps as f64 * 1000.0 / 8.0;
+        let burst = (rate * burst.as_secs_f64()).max(1500.0);
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+    }
+
+    // time until the bucket has enough tokens to send this many bytes
+    fn wait_time(&self, bytes: usize) -> Duration {
+        let needed = (bytes as f64).min(self.burst) - self.tokens;
+        Duration::from_secs_f64(needed.max(0.0) / self.rate)
+    }
+
+    fn consume(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+// An object's place in a waiting set: the one with the highest priority first
+// (higher values are more important, as in the mappings), then in order of arrival.
+type Key = (Reverse<u64>, u64);
+
+struct State {
+    bucket: Option<TokenBucket>,
+    // the objects waiting for the session's tokens
+    waiting: BTreeSet<Key>,
+    next: u64,
+    // the object that got the session's tokens and waits for the global ones,
+    // with its key in the global waiting set
+    global: Option<(Key, Key)>,
+    dropped: u64,
+}
+
+// The egress rate limits of a session, shared by the forwarders of its subscriptions,
+// and the global limit shared by all sessions.
+//
+// When a limit is hit, the objects are sent in order of priority, so the less important
+// ones are delayed. An object first waits for its session's limit, then it competes with
+// the other sessions' objects for the global limit, again in order of priority.
+// Objects that would be delayed longer than the maximum delay are dropped, except the
+// first object of a GOP, which is only delayed since the GOP depends on it.
+// Only the init and catalog tracks, sent once per subscription, aren't limited.
+#[derive(Clone)]
+pub struct Egress {
+    state: Arc<Mutex<State>>,
+    notify: Arc<Notify>,
+    global: Option<GlobalEgress>,
+    max_delay: Duration,
+}
+
+// The global egress limit, shared by the sessions.
+#[derive(Clone)]
+pub struct GlobalEgress {
+    state: Arc<Mutex<GlobalState>>,
+    notify: Arc<Notify>,
+}
+
+struct GlobalState {
+    bucket: TokenBucket,
+    // the objects of all sessions that got their session's tokens
+    waiting: BTreeSet<Key>,
+    next: u64,
+}
+
+impl GlobalEgress {
+    pub fn new(config: &EgressConfig) -> Option<Self> {
+        let burst = Duration::from_millis(config.burst);
+        config.global_rate.map(|kbps| Self {
+            state: Arc::new(Mutex::new(GlobalState {
+                bucket: TokenBucket::new(kbps, burst),
+                waiting: BTreeSet::new(),
+                next: 0,
+            })),
+            notify: Arc::new(Notify::new()),
+        })
+    }
+}
+
+impl Egress {
+    // returns None if the egress isn't limited
+    pub fn new(config: &EgressConfig, global: Option<GlobalEgress>) -> Option<Self> {
+        if config.session_rate.is_none() && global.is_none() {
+            return None;
+        }
+
+        let burst = Duration::from_millis(config.burst);
+        let state = State {
+            bucket: config
+                .session_rate
+                .map(|kbps| TokenBucket::new(kbps, burst)),
+            waiting: BTreeSet::new(),
+            next: 0,
+            global: None,
+            dropped: 0,
+        };
+        Some(Self {
+            state: Arc::new(Mutex::new(state)),
+            notify: Arc::new(Notify::new()),
+            global,
+            max_delay: Duration::from_millis(config.max_delay),
+        })
+    }
+
+    // Waits until the object can be sent within the limits.
+    // Returns false if a droppable object would be delayed longer than the maximum delay.
+    pub async fn acquire(&self, priority: u64, bytes: usize, droppable: bool) -> bool {
+        let deadline = Instant::now() + self.max_delay;
+        let key = {
+            let mut state = self.state.lock().unwrap();
+            let key = (Reverse(priority), state.next);
+            state.next += 1;
+            state.waiting.insert(key);
+            key
+        };
+        // removes the object from the queues, even if the forwarder is dropped while waiting
+        let _waiting = Waiting { egress: self, key };
+
+        loop {
+            let notified = self.notify.notified();
+            let global_notified = self.global.as_ref().map(|global| global.notify.notified());
+            let now = Instant::now();
+            let wait = self.try_consume(key, bytes, now);
+            if wait == Some(Duration::ZERO) {
+                return true;
+            }
+
+            let notified = async {
+                match global_notified {
+                    Some(global_notified) => tokio::select! {
+                        _ = notified => {},
+                        _ = global_notified => {},
+                    },
+                    None => notified.await,
+                }
+            };
+            match wait {
+                // an object with a higher priority goes first
+                None if droppable => {
+                    if now >= deadline {
+                        return self.drop_object();
+                    }
+                    tokio::select! {
+                        _ = notified => {},
+                        _ = tokio::time::sleep_until(deadline) => {},
+                    }
+                }
+                None => notified.await,
+                Some(wait) if droppable && now + wait > deadline => return self.drop_object(),
+                Some(wait) => tokio::time::sleep(wait).await,
+            }
+        }
+    }
+
+    // Spends the tokens if the object is next and there are enough tokens.
+    // Returns the time until there are enough tokens, None if another object is next.
+    fn try_consume(&self, key: Key, bytes: usize, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+
+        // the session's limit
+        if state.global.is_none() {
+            if state.waiting.first() != Some(&key) {
+                return None;
+            }
+            if let Some(bucket) = &mut state.bucket {
+                bucket.refill(now);
+                let wait = bucket.wait_time(bytes);
+                if !wait.is_zero() {
+                    return Some(wait);
+                }
+            }
+            let Some(global) = &self.global else {
+                consume(&mut state, None, key, bytes);
+                return Some(Duration::ZERO);
+            };
+            // the tokens stay with the object while it waits for the global limit,
+            // as no other object of the session is let through meanwhile
+            let mut global = global.state.lock().unwrap();
+            let global_key = (key.0, global.next);
+            global.next += 1;
+            global.waiting.insert(global_key);
+            state.global = Some((key, global_key));
+        }
+
+        // the global limit
+        let (waiting, global_key) = state.global?;
+        if waiting != key {
+            return None;
+        }
+        let global = self.global.as_ref()?;
+        let mut global_state = global.state.lock().unwrap();
+        if global_state.waiting.first() != Some(&global_key) {
+            return None;
+        }
+        global_state.bucket.refill(now);
+        let wait = global_state.bucket.wait_time(bytes);
+        if wait.is_zero() {
+            consume(&mut state, Some(&mut global_state), key, bytes);
+            global.notify.notify_waiters();
+        }
+        Some(wait)
+    }
+
+    // Relays a datagram track within the limits, the datagrams delayed too long are dropped.
+    pub async fn relay_datagrams(
+        &self,
+        mut source: DatagramsReader,
+        mut writer: DatagramsWriter,
+    ) -> anyhow::Result<()> {
+        while let Some(datagram) = source.read().await? {
+            if self
+                .acquire(datagram.priority, datagram.payload.len(), true)
+                .await
+            {
+                writer.write(datagram)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn drop_object(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.dropped += 1;
+        log::debug!("dropping delayed object: dropped={}", state.dropped);
+        false
+    }
+}
+
+// Spends the tokens of the limits and removes the object from the waiting sets.
+fn consume(state: &mut State, global: Option<&mut GlobalState>, key: Key, bytes: usize) {
+    if let Some(bucket) = &mut state.bucket {
+        bucket.consume(bytes);
+    }
+    state.waiting.remove(&key);
+    if let (Some(global), Some((_, global_key))) = (global, state.global.take()) {
+        global.bucket.consume(bytes);
+        global.waiting.remove(&global_key);
+    }
+}
+
+struct Waiting<'a> {
+    egress: &'a Egress,
+    key: Key,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut state = self.egress.state.lock().unwrap();
+        state.waiting.remove(&self.key);
+        if let Some((key, global_key)) = state.global {
+            if key == self.key {
+                state.global = None;
+                if let Some(global) = &self.egress.global {
+                    global.state.lock().unwrap().waiting.remove(&global_key);
+                    global.notify.notify_waiters();
+                }
+            }
+        }
+        drop(state);
+        self.egress.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_starts_full() {
+        // 8 Mbit/s, i.e. 1 MB/s, with a burst of 100 ms
+        let bucket = TokenBucket::new(8000, Duration::from_millis(100));
+        assert_eq!(bucket.wait_time(100_000), Duration::ZERO);
+        assert_eq!(bucket.wait_time(200_000), Duration::ZERO);
+    }
+
+    #[test]
+    fn token_bucket_refills_at_rate() {
+        let mut bucket = TokenBucket::new(8000, Duration::from_millis(100));
+        let now = bucket.updated;
+        bucket.consume(100_000);
+        assert_eq!(bucket.wait_time(50_000), Duration::from_millis(50));
+
+        bucket.refill(now + Duration::from_millis(50));
+        assert_eq!(bucket.wait_time(50_000), Duration::ZERO);
+        // never more than the burst
+        bucket.refill(now + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, bucket.burst);
+    }
+
+    #[test]
+    fn token_bucket_large_object_leaves_debt() {
+        let mut bucket = TokenBucket::new(8000, Duration::from_millis(100));
+        // objects larger than the burst only wait for a full bucket
+        assert_eq!(bucket.wait_time(500_000), Duration::ZERO);
+        bucket.consume(500_000);
+        assert_eq!(bucket.wait_time(1000), Duration::from_millis(401));
+    }
+
+    #[test]
+    fn token_bucket_minimum_burst() {
+        let bucket = TokenBucket::new(8, Duration::from_millis(1));
+        assert_eq!(bucket.burst, 1500.0);
+    }
+
+    // 80 kbit/s, i.e. 10 kB/s, with the minimum burst of 1500 bytes
+    fn config(session_rate: Option<u64>, global_rate: Option<u64>) -> EgressConfig {
+        EgressConfig {
+            session_rate,
+            global_rate,
+            burst: 0,
+            max_delay: 500,
+        }
+    }
+
+    // acquires for each (priority, bytes) in order, returns the priorities in the order sent
+    async fn send_order(egresses: Vec<(Egress, u64)>) -> Vec<u64> {
+        let (sent, mut order) = tokio::sync::mpsc::unbounded_channel();
+        let mut tasks = Vec::new();
+        for (egress, priority) in egresses {
+            let sent = sent.clone();
+            tasks.push(tokio::spawn(async move {
+                assert!(egress.acquire(priority, 500, false).await);
+                sent.send(priority).unwrap();
+            }));
+            // queued in order of arrival
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        drop(sent);
+        let mut priorities = Vec::new();
+        while let Some(priority) = order.recv().await {
+            priorities.push(priority);
+        }
+        priorities
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_objects_sent_by_priority() {
+        let egress = Egress::new(&config(Some(80), None), None).unwrap();
+        assert!(egress.acquire(0, 1500, false).await);
+
+        let egresses = [1, 3, 2, 3].map(|priority| (egress.clone(), priority));
+        let order = send_order(egresses.to_vec()).await;
+        assert_eq!(order, vec![3, 3, 2, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn global_limit_sends_by_priority_across_sessions() {
+        let config = config(None, Some(80));
+        let global = GlobalEgress::new(&config);
+        let first = Egress::new(&config, global.clone()).unwrap();
+        let second = Egress::new(&config, global).unwrap();
+        assert!(first.acquire(0, 1500, false).await);
+
+        let egresses = vec![
+            (first.clone(), 2),
+            (first, 1),
+            (second.clone(), 5),
+            (second, 4),
+        ];
+        let order = send_order(egresses).await;
+        assert_eq!(order, vec![5, 4, 2, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delayed_objects_dropped() {
+        let mut config = config(Some(80), None);
+        config.max_delay = 20;
+        let egress = Egress::new(&config, None).unwrap();
+        assert!(egress.acquire(0, 1500, false).await);
+
+        // 500 bytes take 50 ms
+        let start = Instant::now();
+        assert!(!egress.acquire(0, 500, true).await);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        // the first object of a GOP is only delayed
+        assert!(egress.acquire(0, 500, false).await);
+        assert_eq!(start.elapsed(), Duration::from_millis(50));
+        assert_eq!(egress.state.lock().unwrap().dropped, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropped_object_leaves_queue() {
+        let mut config = config(Some(80), None);
+        config.max_delay = 20;
+        let egress = Egress::new(&config, None).unwrap();
+        assert!(egress.acquire(0, 1500, false).await);
+
+        // the dropped object doesn't spend tokens or block the next one
+        assert!(!egress.acquire(5, 500, true).await);
+        let waiting = egress.clone();
+        let next = tokio::spawn(async move { waiting.acquire(0, 500, false).await });
+        assert!(next.await.unwrap());
+        assert!(egress.state.lock().unwrap().waiting.is_empty());
+    }
+}
@@ -0,0 +1,379 @@
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use moq_transport::serve::{
+    Group, GroupWriter, GroupsWriter, Object, ObjectsWriter, StreamGroupWriter, StreamWriter,
+    TrackWriter,
+};
+
+use crate::{
+    cache::{CacheMode, CachedObject, GopCacheReader, JoinMode},
+    congestion::FrameDropper,
+    deadline::{DeadlineFilter, Released},
+    egress::Egress,
+    qlog::Qlog,
+    sendlog::{Outcome, SendLog},
+    stats::SessionStats,
+    video::{mark_undecodable, parse_frame_header, FrameType},
+};
+
+// Forwards the cached objects of a track to a subscriber's copy of the track,
+// starting at the join position and dropping frames depending on the subscriber.
+pub struct Forwarder {
+    source: GopCacheReader,
+    sink: Sink,
+    stats: SessionStats,
+
+    priority: PriorityPolicy,
+    deadline: Option<DeadlineFilter>,
+    dropper: Option<FrameDropper>,
+    egress: Option<Egress>,
+
+    qlog: Qlog,
+    track: String,
+    send_log: Option<SendLog>,
+    // when the current group of the subscriber's copy was started
+    group_started: Option<SystemTime>,
+
+    // whether the subscriber hasn't received the start of a GOP yet
+    joining: bool,
+    // whether a frame the rest of the GOP depends on was dropped by the egress limits
+    skip_to_gop: bool,
+    // whether all objects were read from the cache
+    source_done: bool,
+}
+
+impl Forwarder {
+    pub fn new(source: GopCacheReader, sink: Sink, stats: SessionStats, join: JoinMode) -> Self {
+        Self {
+            source,
+            sink,
+            stats,
+            priority: PriorityPolicy::Mapping,
+            deadline: None,
+            dropper: None,
+            egress: None,
+            qlog: Qlog::default(),
+            track: String::new(),
+            send_log: None,
+            group_started: None,
+            joining: join == JoinMode::LiveEdge,
+            skip_to_gop: false,
+            source_done: false,
+        }
+    }
+
+    pub fn with_priority(mut self, priority: PriorityPolicy) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_deadline(mut self, deadline: DeadlineFilter) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn with_dropper(mut self, dropper: FrameDropper) -> Self {
+        self.dropper = Some(dropper);
+        self
+    }
+
+    pub fn with_egress(mut self, egress: Egress) -> Self {
+        self.egress = Some(egress);
+        self
+    }
+
+    pub fn with_qlog(mut self, qlog: Qlog, track: String) -> Self {
+        self.qlog = qlog;
+        self.track = track;
+        self
+    }
+
+    pub fn with_send_log(mut self, send_log: SendLog) -> Self {
+        self.send_log = Some(send_log);
+        self
+    }
+
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        while let Some(object) = self.next().await? {
+            if object.gop_start {
+                self.joining = false;
+                self.skip_to_gop = false;
+            }
+            if self.skip_to_gop {
+                self.dropped(&object, Outcome::Egress);
+                continue;
+            }
+            if let Some(dropper) = &mut self.dropper {
+                if !dropper.forward(&object.payload)? {
+                    self.dropped(&object, Outcome::Congestion);
+                    continue;
+                }
+            }
+
+            // joined at the live edge, the frames before the next keyframe are only
+            // sent so the player can show progress, it can't decode them
+            let payload = match self.joining {
+                true => mark_undecodable(&object.payload),
+                false => object.payload.clone(),
+            };
+            if let Some(egress) = &self.egress {
+                if !egress
+                    .acquire(object.priority, payload.len(), !object.gop_start)
+                    .await
+                {
+                    // the following frames of the GOP depend on anything but a B-frame
+                    self.skip_to_gop = !matches!(
+                        parse_frame_header(&object.payload),
+                        Ok(header) if header.frame_type == FrameType::B
+                    );
+                    self.dropped(&object, Outcome::Egress);
+                    continue;
+                }
+            }
+            if object.gop_start && self.source.take_split() {
+                self.sink.split();
+            }
+            let length = payload.len();
+            let enqueued = SystemTime::now();
+            self.stats.enqueued(length);
+            let written = match self.sink.write(&object, payload) {
+                Ok(written) => written,
+                Err(err) => {
+                    self.dropped(&object, Outcome::Reset);
+                    return Err(err);
+                }
+            };
+            if written.opened {
+                self.group_started = Some(enqueued);
+                self.qlog
+                    .group_opened(&self.track, &object, written.group_id);
+            }
+            let wire = (written.group_id, written.object_id);
+            self.qlog.object_sent(&self.track, &object, wire, length);
+
+            if let Some(send_log) = &mut self.send_log {
+                // objects are sent on their own stream
+                let started = match self.sink {
+                    Sink::Objects(_) => Some(enqueued),
+                    _ => self.group_started,
+                };
+                let delay = self.stats.queue_delay();
+                send_log.log(&object, Outcome::Sent, Some(enqueued), started, delay);
+            }
+        }
+        Ok(())
+    }
+
+    // The next object to forward. With a deadline, objects wait in the deadline filter
+    // until the session can send them, the ones expiring meanwhile are dropped.
+    async fn next(&mut self) -> anyhow::Result<Option<CachedObject>> {
+        let policy = self.priority;
+        loop {
+            let Some(deadline) = &mut self.deadline else {
+                return Ok(self.source.next().await?.map(|object| policy.apply(object)));
+            };
+            let released = tokio::select! {
+                object = self.source.next(), if !self.source_done => {
+                    match object? {
+                        Some(object) => deadline.push(policy.apply(object))?,
+                        None => self.source_done = true,
+                    }
+                    continue;
+                }
+                released = deadline.release(), if !deadline.is_empty() => released,
+                else => return Ok(None),
+            };
+            match released {
+                Released::Forward(object) => return Ok(Some(object)),
+                Released::Expired(object) => self.dropped(&object, Outcome::Deadline),
+            }
+        }
+    }
+
+    fn dropped(&mut self, object: &CachedObject, outcome: Outcome) {
+        self.qlog
+            .object_dropped(&self.track, object, outcome.as_str());
+        if let Some(send_log) = &mut self.send_log {
+            let delay = self.stats.queue_delay();
+            send_log.log(object, outcome, None, None, delay);
+        }
+    }
+}
+
+// How the priorities of a broadcast's objects are set when they are sent to a subscriber.
+// Higher values are more important, as in the mappings.
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PriorityPolicy {
+    // the priorities assigned by the mapping
+    #[default]
+    Mapping,
+    // newer groups before older ones, so a congested subscriber catches up with the live edge
+    NewestFirst,
+    // the same priority for all objects, so they are sent in order
+    Fifo,
+}
+
+impl PriorityPolicy {
+    fn apply(self, mut object: CachedObject) -> CachedObject {
+        object.priority = match self {
+            PriorityPolicy::Mapping => object.priority,
+            PriorityPolicy::NewestFirst => object.group_id,
+            PriorityPolicy::Fifo => 0,
+        };
+        object
+    }
+}
+
+// Writes objects to a subscriber's copy of a track, in the mode of the original track.
+// Groups keep their original ids, a new one is started whenever the original group changes.
+// Object ids within a group are renumbered from 0 by the transport, except in objects mode.
+pub enum Sink {
+    Stream {
+        writer: StreamWriter,
+        group: Option<OpenGroup<StreamGroupWriter>>,
+        ids: GroupIds,
+    },
+    Groups {
+        writer: GroupsWriter,
+        group: Option<OpenGroup<GroupWriter>>,
+        ids: GroupIds,
+    },
+    Objects(ObjectsWriter),
+}
+
+// The current group of a subscriber's copy of a track.
+pub struct OpenGroup<W> {
+    writer: W,
+    // the id of the original group
+    source_id: u64,
+    // the id on the wire
+    group_id: u64,
+    // the number of objects written, i.e. the id of the next one
+    objects: u64,
+}
+
+impl<W> OpenGroup<W> {
+    fn new(writer: W, source_id: u64, group_id: u64) -> Self {
+        Self {
+            writer,
+            source_id,
+            group_id,
+            objects: 0,
+        }
+    }
+
+    fn next(&mut self) -> Written {
+        self.objects += 1;
+        Written {
+            group_id: self.group_id,
+            object_id: self.objects - 1,
+            opened: self.objects == 1,
+        }
+    }
+}
+
+// Where an object was written to in a subscriber's copy of a track.
+pub struct Written {
+    pub group_id: u64,
+    pub object_id: u64,
+    // whether a new group was started for the object
+    pub opened: bool,
+}
+
+impl Sink {
+    pub fn new(track: TrackWriter, mode: CacheMode) -> anyhow::Result<Self> {
+        Ok(match mode {
+            CacheMode::Stream => Sink::Stream {
+                writer: track.stream(0)?,
+                group: None,
+                ids: GroupIds::default(),
+            },
+            CacheMode::Groups => Sink::Groups {
+                writer: track.groups()?,
+                group: None,
+                ids: GroupIds::default(),
+            },
+            CacheMode::Objects => Sink::Objects(track.objects()?),
+        })
+    }
+
+    // starts a new group at the next object
+    fn split(&mut self) {
+        match self {
+            Sink::Stream { group, .. } => *group = None,
+            Sink::Groups { group, .. } => *group = None,
+            Sink::Objects(_) => {}
+        }
+    }
+
+    fn write(&mut self, object: &CachedObject, payload: Bytes) -> anyhow::Result<Written> {
+        let written = match self {
+            Sink::Stream { writer, group, ids } => {
+                let group = match group {
+                    Some(group) if group.source_id == object.group_id => group,
+                    _ => {
+                        let group_id = ids.next(object.group_id);
+                        let output = writer.create(group_id)?;
+                        group.insert(OpenGroup::new(output, object.group_id, group_id))
+                    }
+                };
+                group.writer.write(payload)?;
+                group.next()
+            }
+            Sink::Groups { writer, group, ids } => {
+                let group = match group {
+                    Some(group) if group.source_id == object.group_id => group,
+                    _ => {
+                        let group_id = ids.next(object.group_id);
+                        let output = writer.create(Group {
+                            group_id,
+                            priority: object.priority,
+                        })?;
+                        group.insert(OpenGroup::new(output, object.group_id, group_id))
+                    }
+                };
+                group.writer.write(payload)?;
+                group.next()
+            }
+            Sink::Objects(writer) => {
+                writer.write(
+                    Object {
+                        group_id: object.group_id,
+                        object_id: object.object_id,
+                        priority: object.priority,
+                    },
+                    payload,
+                )?;
+                Written {
+                    group_id: object.group_id,
+                    object_id: object.object_id,
+                    opened: false,
+                }
+            }
+        };
+        Ok(written)
+    }
+}
+
+// The group ids of a subscriber's copy of a track. They are the original ids, unless a group
+// was split or the original ids went backwards, e.g. after the source restarted. Then the
+// following ids are shifted, as the ids of the copy have to be increasing.
+#[derive(Default)]
+pub struct GroupIds {
+    offset: u64,
+    last: Option<u64>,
+}
+
+impl GroupIds {
+    fn next(&mut self, group_id: u64) -> u64 {
+        let mut id = group_id + self.offset;
+        if let Some(last) = self.last.filter(|last| id <= *last) {
+            self.offset += last + 1 - id;
+            id = last + 1;
+        }
+        self.last = Some(id);
+        id
+    }
+}
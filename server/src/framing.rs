@@ -0,0 +1,292 @@
+use bytes::{Buf, BytesMut};
+
+use crate::video::declared_item_size;
+
+// Marks the start of an item in the framed ingest format:
+// marker, [rendition index u8], item, CRC-32 (IEEE) of the index and item as u32.
+// Inputs without markers use the plain format, in which corrupt input can't be recovered from.
+pub const SYNC_MARKER: [u8; 4] = *b"MOQI";
+const CRC_SIZE: usize = 4;
+
+pub enum Next {
+    // more data is needed
+    Incomplete,
+    Item { index: usize, item: BytesMut },
+    // corrupt data was skipped, up to the next sync marker
+    Skipped(usize),
+}
+
+// Splits an input into items. Once a sync marker was seen, corrupt data
+// (unknown item types, oversized items or CRC mismatches) is skipped
+// by scanning for the next sync marker.
+pub struct ItemParser {
+    indexed: bool,
+    max_item_size: usize,
+    framed: bool,
+    pub skipped_bytes: u64,
+}
+
+impl ItemParser {
+    pub fn new(indexed: bool, max_item_size: usize) -> Self {
+        Self {
+            indexed,
+            max_item_size,
+            framed: false,
+            skipped_bytes: 0,
+        }
+    }
+
+    pub fn next(&mut self, buf: &mut BytesMut) -> anyhow::Result<Next> {
+        let prefix = self.indexed as usize;
+        if buf.len() < SYNC_MARKER.len() && SYNC_MARKER.starts_with(&buf[..]) {
+            return Ok(Next::Incomplete);
+        }
+        if buf.starts_with(&SYNC_MARKER) {
+            self.framed = true;
+        }
+        if !self.framed {
+            return self.next_plain(buf);
+        }
+        if !buf.starts_with(&SYNC_MARKER) {
+            return Ok(self.skip(buf, 0));
+        }
+
+        let body = &buf[SYNC_MARKER.len()..];
+        if body.len() <= prefix {
+            return Ok(Next::Incomplete);
+        }
+        let size = match declared_item_size(&body[prefix..]) {
+            Ok(None) => return Ok(Next::Incomplete),
+            Ok(Some(size)) if size <= self.max_item_size => size,
+            _ => return Ok(self.skip(buf, 1)),
+        };
+        if body.len() < prefix + size + CRC_SIZE {
+            return Ok(Next::Incomplete);
+        }
+
+        let (payload, crc) = body[..prefix + size + CRC_SIZE].split_at(prefix + size);
+        if crc32fast::hash(payload) != u32::from_be_bytes(crc.try_into().unwrap()) {
+            return Ok(self.skip(buf, 1));
+        }
+
+        buf.advance(SYNC_MARKER.len());
+        let index = match self.indexed {
+            true => buf.get_u8() as usize,
+            false => 0,
+        };
+        let item = buf.split_to(size);
+        buf.advance(CRC_SIZE);
+        Ok(Next::Item { index, item })
+    }
+
+    fn next_plain(&mut self, buf: &mut BytesMut) -> anyhow::Result<Next> {
+        let prefix = self.indexed as usize;
+        if buf.len() <= prefix {
+            return Ok(Next::Incomplete);
+        }
+        let size = match declared_item_size(&buf[prefix..])? {
+            Some(size) if size > self.max_item_size => {
+                anyhow::bail!("item of {} bytes exceeds the maximum size", size)
+            }
+            Some(size) if prefix + size <= buf.len() => size,
+            _ => return Ok(Next::Incomplete),
+        };
+        let index = match self.indexed {
+            true => buf.get_u8() as usize,
+            false => 0,
+        };
+        Ok(Next::Item {
+            index,
+            item: buf.split_to(size),
+        })
+    }
+
+    // drops the data before the next sync marker found after `from`,
+    // keeping a possibly incomplete marker at the end of the buffer
+    fn skip(&mut self, buf: &mut BytesMut, from: usize) -> Next {
+        let skipped = buf[from..]
+            .windows(SYNC_MARKER.len())
+            .position(|window| window == SYNC_MARKER)
+            .map(|position| from + position)
+            .unwrap_or_else(|| buf.len().saturating_sub(SYNC_MARKER.len() - 1).max(from));
+        if skipped == 0 {
+            return Next::Incomplete;
+        }
+        buf.advance(skipped);
+        self.skipped_bytes += skipped as u64;
+        Next::Skipped(skipped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn init(data: &[u8]) -> Vec<u8> {
+        let mut item = vec![0x00];
+        item.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        item.extend_from_slice(data);
+        item
+    }
+
+    fn framed(index: Option<u8>, item: &[u8]) -> Vec<u8> {
+        let mut payload: Vec<u8> = index.into_iter().collect();
+        payload.extend_from_slice(item);
+        let mut framed = SYNC_MARKER.to_vec();
+        framed.extend_from_slice(&payload);
+        framed.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+        framed
+    }
+
+    fn expect_item(next: Next) -> (usize, BytesMut) {
+        match next {
+            Next::Item { index, item } => (index, item),
+            Next::Incomplete => panic!("expected an item, got incomplete"),
+            Next::Skipped(skipped) => panic!("expected an item, skipped {}", skipped),
+        }
+    }
+
+    #[test]
+    fn plain_items() {
+        let mut parser = ItemParser::new(false, 1024);
+        let mut buf = BytesMut::from(&[init(b"ab"), init(b"cde")].concat()[..]);
+
+        assert_eq!(
+            &expect_item(parser.next(&mut buf).unwrap()).1[..],
+            &init(b"ab")[..]
+        );
+        assert_eq!(
+            &expect_item(parser.next(&mut buf).unwrap()).1[..],
+            &init(b"cde")[..]
+        );
+        assert!(matches!(parser.next(&mut buf).unwrap(), Next::Incomplete));
+    }
+
+    #[test]
+    fn plain_item_incomplete() {
+        let mut parser = ItemParser::new(false, 1024);
+        let item = init(b"abcd");
+        let mut buf = BytesMut::from(&item[..4]);
+        assert!(matches!(parser.next(&mut buf).unwrap(), Next::Incomplete));
+
+        buf.extend_from_slice(&item[4..]);
+        assert_eq!(
+            &expect_item(parser.next(&mut buf).unwrap()).1[..],
+            &item[..]
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn plain_indexed_item() {
+        let mut parser = ItemParser::new(true, 1024);
+        let mut buf = BytesMut::from(&[&[3u8][..], &init(b"ab")].concat()[..]);
+
+        let (index, item) = expect_item(parser.next(&mut buf).unwrap());
+        assert_eq!(index, 3);
+        assert_eq!(&item[..], &init(b"ab")[..]);
+    }
+
+    #[test]
+    fn plain_oversized_item_fails() {
+        let mut parser = ItemParser::new(false, 4);
+        let mut buf = BytesMut::from(&init(b"abcd")[..]);
+        assert!(parser.next(&mut buf).is_err());
+    }
+
+    #[test]
+    fn framed_indexed_item() {
+        let mut parser = ItemParser::new(true, 1024);
+        let mut buf = BytesMut::from(&framed(Some(2), &init(b"ab"))[..]);
+
+        let (index, item) = expect_item(parser.next(&mut buf).unwrap());
+        assert_eq!(index, 2);
+        assert_eq!(&item[..], &init(b"ab")[..]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn framed_item_split_in_marker() {
+        let mut parser = ItemParser::new(false, 1024);
+        let data = framed(None, &init(b"ab"));
+        let mut buf = BytesMut::from(&data[..2]);
+        assert!(matches!(parser.next(&mut buf).unwrap(), Next::Incomplete));
+
+        buf.extend_from_slice(&data[2..]);
+        assert_eq!(
+            &expect_item(parser.next(&mut buf).unwrap()).1[..],
+            &init(b"ab")[..]
+        );
+    }
+
+    #[test]
+    fn crc_mismatch_is_skipped() {
+        let mut parser = ItemParser::new(false, 1024);
+        let mut corrupt = framed(None, &init(b"ab"));
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xff;
+        let skipped = corrupt.len();
+        let mut buf = BytesMut::from(&[corrupt, framed(None, &init(b"cd"))].concat()[..]);
+
+        assert!(matches!(parser.next(&mut buf).unwrap(), Next::Skipped(n) if n == skipped));
+        assert_eq!(
+            &expect_item(parser.next(&mut buf).unwrap()).1[..],
+            &init(b"cd")[..]
+        );
+        assert_eq!(parser.skipped_bytes, skipped as u64);
+    }
+
+    #[test]
+    fn garbage_between_items_is_skipped() {
+        let mut parser = ItemParser::new(false, 1024);
+        let data = [
+            framed(None, &init(b"ab")),
+            b"garbage".to_vec(),
+            framed(None, &init(b"cd")),
+        ]
+        .concat();
+        let mut buf = BytesMut::from(&data[..]);
+
+        assert_eq!(
+            &expect_item(parser.next(&mut buf).unwrap()).1[..],
+            &init(b"ab")[..]
+        );
+        assert!(matches!(parser.next(&mut buf).unwrap(), Next::Skipped(7)));
+        assert_eq!(
+            &expect_item(parser.next(&mut buf).unwrap()).1[..],
+            &init(b"cd")[..]
+        );
+    }
+
+    #[test]
+    fn framed_oversized_item_is_skipped() {
+        let mut parser = ItemParser::new(false, 6);
+        let data = [framed(None, &init(b"abcd")), framed(None, &init(b"a"))].concat();
+        let mut buf = BytesMut::from(&data[..]);
+
+        assert!(matches!(parser.next(&mut buf).unwrap(), Next::Skipped(_)));
+        assert_eq!(
+            &expect_item(parser.next(&mut buf).unwrap()).1[..],
+            &init(b"a")[..]
+        );
+    }
+
+    #[test]
+    fn trailing_partial_marker_is_kept() {
+        let mut parser = ItemParser::new(false, 1024);
+        let data = [framed(None, &init(b"ab")), b"xxxxMO".to_vec()].concat();
+        let mut buf = BytesMut::from(&data[..]);
+
+        expect_item(parser.next(&mut buf).unwrap());
+        assert!(matches!(parser.next(&mut buf).unwrap(), Next::Skipped(3)));
+        assert_eq!(&buf[..], b"xMO");
+        assert!(matches!(parser.next(&mut buf).unwrap(), Next::Incomplete));
+
+        buf.extend_from_slice(&framed(None, &init(b"cd"))[2..]);
+        assert!(matches!(parser.next(&mut buf).unwrap(), Next::Skipped(1)));
+        assert_eq!(
+            &expect_item(parser.next(&mut buf).unwrap()).1[..],
+            &init(b"cd")[..]
+        );
+    }
+}
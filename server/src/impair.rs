@@ -0,0 +1,514 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use bytes::Bytes;
+use serde::Deserialize;
+use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle};
+
+use crate::{config::ImpairConfig, sendlog::millis};
+
+const MAX_PACKET_SIZE: usize = 65536;
+// clients that haven't sent or received anything for this long are forgotten
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+// the address the QUIC endpoint listens on behind the relay
+pub fn endpoint_bind(bind: SocketAddr) -> SocketAddr {
+    let ip = match bind.ip() {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+    };
+    SocketAddr::new(ip, 0)
+}
+
+// A step of a bandwidth trace.
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct TraceStep {
+    // kbit/s
+    pub speed: u64,
+    // seconds
+    pub duration: f64,
+}
+
+pub fn load_trace(path: &Path) -> anyhow::Result<Vec<TraceStep>> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let trace: Vec<TraceStep> = serde_json::from_str(&data)
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+    if trace
+        .iter()
+        .any(|step| step.duration.is_nan() || step.duration < 0.0)
+    {
+        anyhow::bail!("invalid step duration in {}", path.display());
+    }
+    if trace.iter().map(|step| step.duration).sum::<f64>() <= 0.0 {
+        anyhow::bail!("empty bandwidth trace {}", path.display());
+    }
+    Ok(trace)
+}
+
+// The bandwidth from the server to the clients over time, shared by the listeners.
+#[derive(Clone)]
+pub enum Bandwidth {
+    Unlimited,
+    Fixed(u64),
+    // unlimited once the trace ended, like the testbed's simulation, unless looped
+    Trace {
+        steps: Arc<Vec<TraceStep>>,
+        started: Instant,
+        looped: bool,
+    },
+}
+
+impl Bandwidth {
+    // the trace starts right away
+    pub fn new(config: &ImpairConfig) -> anyhow::Result<Self> {
+        config.validate()?;
+        Ok(match (&config.trace, config.bandwidth) {
+            (Some(path), _) => Bandwidth::Trace {
+                steps: Arc::new(load_trace(path)?),
+                started: Instant::now(),
+                looped: config.trace_loop,
+            },
+            (None, Some(kbps)) => Bandwidth::Fixed(kbps),
+            (None, None) => Bandwidth::Unlimited,
+        })
+    }
+
+    // kbit/s, None if unlimited
+    fn at(&self, now: Instant) -> Option<u64> {
+        match self {
+            Bandwidth::Unlimited => None,
+            Bandwidth::Fixed(kbps) => Some(*kbps),
+            Bandwidth::Trace {
+                steps,
+                started,
+                looped,
+            } => {
+                let mut elapsed = now.saturating_duration_since(*started).as_secs_f64();
+                if *looped {
+                    elapsed %= steps.iter().map(|step| step.duration).sum::<f64>();
+                }
+                for step in steps.iter() {
+                    if elapsed < step.duration {
+                        return Some(step.speed);
+                    }
+                    elapsed -= step.duration;
+                }
+                None
+            }
+        }
+    }
+}
+
+// Logs the bandwidth applied to the links as CSV each time it changes, with the time in
+// milliseconds since the unix epoch, so it lines up with the send logs and player metrics.
+// An empty speed means the bandwidth is unlimited.
+pub struct BandwidthLog {
+    writer: BufWriter<File>,
+    bandwidth: Bandwidth,
+}
+
+impl BandwidthLog {
+    pub fn create(path: &Path, bandwidth: Bandwidth) -> anyhow::Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "time_ms,speed_kbps")?;
+        Ok(Self { writer, bandwidth })
+    }
+
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        let (steps, started, looped) = match &self.bandwidth {
+            Bandwidth::Trace {
+                steps,
+                started,
+                looped,
+            } => (steps.clone(), *started, *looped),
+            bandwidth => {
+                let speed = bandwidth.at(Instant::now());
+                return self.write(SystemTime::now(), speed);
+            }
+        };
+
+        // the wall-clock time the trace started at
+        let epoch = SystemTime::now() - started.elapsed();
+        let mut at = started;
+        loop {
+            for step in steps.iter() {
+                self.write(epoch + (at - started), Some(step.speed))?;
+                at += Duration::from_secs_f64(step.duration);
+                tokio::time::sleep_until(at.into()).await;
+            }
+            if !looped {
+                return self.write(epoch + (at - started), None);
+            }
+        }
+    }
+
+    fn write(&mut self, time: SystemTime, speed: Option<u64>) -> anyhow::Result<()> {
+        let speed = speed.map(|speed| speed.to_string()).unwrap_or_default();
+        writeln!(self.writer, "{:.3},{}", millis(time), speed)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// Reproducible pseudo-random numbers (xorshift64*).
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: Option<u64>) -> Self {
+        let seed = seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos() as u64
+        });
+        Self(seed.max(1))
+    }
+
+    // uniform in [0, 1)
+    fn next(&mut self) -> f64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let value = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        (value >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+struct Packet {
+    data: Bytes,
+    socket: Arc<UdpSocket>,
+    // None for a socket connected to the destination
+    destination: Option<SocketAddr>,
+}
+
+// One direction of the emulated link: packets are lost at random, queued behind the
+// bandwidth limit (dropped when the queue is full), then delayed. Jitter doesn't reorder
+// packets, a packet never leaves before the previous one.
+struct Link {
+    delay: Duration,
+    jitter: Duration,
+    loss: f64,
+    bandwidth: Bandwidth,
+    queue: usize,
+    rng: Rng,
+
+    // when the packets being transmitted at the bandwidth limit are done
+    transmitting: VecDeque<Instant>,
+    // when the link is free to transmit the next packet
+    free: Instant,
+    // the packets in flight by the time they arrive
+    in_flight: VecDeque<(Instant, Packet)>,
+}
+
+impl Link {
+    fn new(config: &ImpairConfig, bandwidth: Bandwidth, rng: Rng) -> Self {
+        Self {
+            delay: Duration::from_millis(config.delay),
+            jitter: Duration::from_millis(config.jitter),
+            loss: config.loss,
+            bandwidth,
+            queue: config.queue,
+            rng,
+            transmitting: VecDeque::new(),
+            free: Instant::now(),
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    async fn run(mut self, mut packets: mpsc::UnboundedReceiver<Packet>) {
+        loop {
+            let next = self.in_flight.front().map(|(arrival, _)| *arrival);
+            let wake = next.unwrap_or_else(Instant::now);
+            tokio::select! {
+                packet = packets.recv() => match packet {
+                    Some(packet) => self.send(packet),
+                    None => return,
+                },
+                _ = tokio::time::sleep_until(wake.into()), if next.is_some() => {
+                    self.deliver().await;
+                },
+            }
+        }
+    }
+
+    fn send(&mut self, packet: Packet) {
+        if self.loss > 0.0 && self.rng.next() < self.loss {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut sent = now;
+        if let Some(kbps) = self.bandwidth.at(now) {
+            while self.transmitting.front().is_some_and(|done| *done <= now) {
+                self.transmitting.pop_front();
+            }
+            if self.transmitting.len() >= self.queue {
+                return;
+            }
+            let bytes_per_sec = (kbps.max(1) * 1000 / 8) as f64;
+            let transmission = Duration::from_secs_f64(packet.data.len() as f64 / bytes_per_sec);
+            sent = self.free.max(now) + transmission;
+            self.free = sent;
+            self.transmitting.push_back(sent);
+        }
+
+        let jitter = (self.rng.next() * 2.0 - 1.0) * self.jitter.as_secs_f64();
+        let delay = (self.delay.as_secs_f64() + jitter).max(0.0);
+        let mut arrival = sent + Duration::from_secs_f64(delay);
+        if let Some((last, _)) = self.in_flight.back() {
+            arrival = arrival.max(*last);
+        }
+        self.in_flight.push_back((arrival, packet));
+    }
+
+    async fn deliver(&mut self) {
+        let now = Instant::now();
+        while self
+            .in_flight
+            .front()
+            .is_some_and(|(arrival, _)| *arrival <= now)
+        {
+            let (_, packet) = self.in_flight.pop_front().unwrap();
+            let res = match packet.destination {
+                Some(destination) => packet.socket.send_to(&packet.data, destination).await,
+                None => packet.socket.send(&packet.data).await,
+            };
+            if let Err(err) = res {
+                log::debug!("failed to relay packet: {}", err);
+            }
+        }
+    }
+}
+
+// Relays the packets between the clients and a QUIC endpoint listening on localhost,
+// through an emulated link in each direction. The endpoint sees each client as a
+// different port on localhost, so the server refuses to combine it with auth or per-IP limits.
+pub struct Relay {
+    socket: Arc<UdpSocket>,
+    endpoint: SocketAddr,
+    config: ImpairConfig,
+    bandwidth: Bandwidth,
+}
+
+impl Relay {
+    pub async fn bind(
+        bind: SocketAddr,
+        endpoint: SocketAddr,
+        config: ImpairConfig,
+        bandwidth: Bandwidth,
+    ) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(bind)
+            .await
+            .with_context(|| format!("failed to bind impairment relay to {}", bind))?;
+        log::info!(
+            "emulating network impairments on {}: delay={}ms jitter={}ms loss={} bandwidth={:?} trace={:?}",
+            bind,
+            config.delay,
+            config.jitter,
+            config.loss,
+            config.bandwidth,
+            config.trace,
+        );
+        Ok(Self {
+            socket: Arc::new(socket),
+            endpoint,
+            config,
+            bandwidth,
+        })
+    }
+
+    pub async fn run(self) -> anyhow::Result<()> {
+        // the links draw from separate generators, so each is reproducible on its own
+        let mut rng = Rng::new(self.config.seed);
+        let uplink_rng = Rng::new(Some((rng.next() * u64::MAX as f64) as u64));
+        let (downlink, packets) = mpsc::unbounded_channel();
+        let _downlink_task = Task(tokio::spawn(
+            Link::new(&self.config, self.bandwidth.clone(), rng).run(packets),
+        ));
+        let (uplink, packets) = mpsc::unbounded_channel();
+        let _uplink_task = Task(tokio::spawn(
+            Link::new(&self.config, Bandwidth::Unlimited, uplink_rng).run(packets),
+        ));
+
+        // the socket relaying each client's packets to the endpoint
+        let mut clients: HashMap<SocketAddr, (Arc<UdpSocket>, Task)> = HashMap::new();
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            let (size, client) = self.socket.recv_from(&mut buf).await?;
+
+            let upstream = match clients.get(&client) {
+                Some((socket, task)) if !task.0.is_finished() => socket.clone(),
+                _ => {
+                    clients.retain(|_, (_, task)| !task.0.is_finished());
+                    let socket = match upstream_socket(self.endpoint).await {
+                        Ok(socket) => Arc::new(socket),
+                        Err(err) => {
+                            log::warn!("dropping packet from {}: {:#}", client, err);
+                            continue;
+                        }
+                    };
+                    let task = Task(tokio::spawn(relay_back(
+                        socket.clone(),
+                        self.socket.clone(),
+                        client,
+                        downlink.clone(),
+                    )));
+                    clients.insert(client, (socket.clone(), task));
+                    socket
+                }
+            };
+
+            let packet = Packet {
+                data: Bytes::copy_from_slice(&buf[..size]),
+                socket: upstream,
+                destination: None,
+            };
+            if uplink.send(packet).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// a socket relaying a client's packets, connected to the endpoint
+async fn upstream_socket(endpoint: SocketAddr) -> anyhow::Result<UdpSocket> {
+    let socket = UdpSocket::bind(endpoint_bind(endpoint))
+        .await
+        .context("failed to bind relay socket")?;
+    socket
+        .connect(endpoint)
+        .await
+        .context("failed to connect relay socket")?;
+    Ok(socket)
+}
+
+// Aborts the task once dropped, so the relay's tasks (and sockets) don't outlive it.
+struct Task(JoinHandle<()>);
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+// relays the endpoint's packets for a client to the downlink, until the client is idle
+async fn relay_back(
+    upstream: Arc<UdpSocket>,
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+    downlink: mpsc::UnboundedSender<Packet>,
+) {
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let size = match tokio::time::timeout(CLIENT_TIMEOUT, upstream.recv(&mut buf)).await {
+            Ok(Ok(size)) => size,
+            Ok(Err(err)) => {
+                log::debug!("failed to receive packet for {}: {}", client, err);
+                return;
+            }
+            Err(_) => return,
+        };
+        let packet = Packet {
+            data: Bytes::copy_from_slice(&buf[..size]),
+            socket: socket.clone(),
+            destination: Some(client),
+        };
+        if downlink.send(packet).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(looped: bool, started: Instant) -> Bandwidth {
+        let steps = vec![
+            TraceStep {
+                speed: 1000,
+                duration: 1.0,
+            },
+            TraceStep {
+                speed: 500,
+                duration: 2.0,
+            },
+        ];
+        Bandwidth::Trace {
+            steps: Arc::new(steps),
+            started,
+            looped,
+        }
+    }
+
+    #[test]
+    fn bandwidth_follows_trace() {
+        let started = Instant::now();
+        let bandwidth = trace(false, started);
+
+        assert_eq!(bandwidth.at(started), Some(1000));
+        assert_eq!(
+            bandwidth.at(started + Duration::from_millis(999)),
+            Some(1000)
+        );
+        assert_eq!(bandwidth.at(started + Duration::from_secs(1)), Some(500));
+        assert_eq!(
+            bandwidth.at(started + Duration::from_millis(2999)),
+            Some(500)
+        );
+        // unlimited once the trace ended
+        assert_eq!(bandwidth.at(started + Duration::from_secs(3)), None);
+    }
+
+    #[test]
+    fn bandwidth_loops_trace() {
+        let started = Instant::now();
+        let bandwidth = trace(true, started);
+
+        assert_eq!(
+            bandwidth.at(started + Duration::from_millis(3500)),
+            Some(1000)
+        );
+        assert_eq!(
+            bandwidth.at(started + Duration::from_millis(4500)),
+            Some(500)
+        );
+        assert_eq!(bandwidth.at(started + Duration::from_secs(30)), Some(1000));
+    }
+
+    #[test]
+    fn bandwidth_fixed_and_unlimited() {
+        let now = Instant::now();
+        assert_eq!(Bandwidth::Fixed(800).at(now), Some(800));
+        assert_eq!(Bandwidth::Unlimited.at(now), None);
+    }
+
+    #[test]
+    fn loss_outside_range_is_rejected() {
+        for loss in [-0.1, 1.5, f64::NAN] {
+            let config = ImpairConfig {
+                loss,
+                ..Default::default()
+            };
+            assert!(Bandwidth::new(&config).is_err(), "loss {}", loss);
+        }
+        for loss in [0.0, 0.5, 1.0] {
+            let config = ImpairConfig {
+                loss,
+                ..Default::default()
+            };
+            assert!(Bandwidth::new(&config).is_ok(), "loss {}", loss);
+        }
+    }
+}
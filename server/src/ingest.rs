@@ -0,0 +1,476 @@
+use std::{
+    fmt,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::Context;
+use bytes::BytesMut;
+use futures::{future::LocalBoxFuture, stream::FuturesUnordered, FutureExt, TryStreamExt};
+use serde::{Deserialize, Deserializer};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::{unix::pipe, UnixListener, UnixStream},
+};
+
+use crate::{
+    broadcast::{Broadcast, Rendition},
+    buffer::{BufferConfig, Buffered, IngestBuffer, Pacer},
+    framing::{ItemParser, Next},
+    timeline::{BroadcastTimeline, Timeline},
+    video::{VideoStreamer, DISCONTINUITY_FLAG},
+};
+
+// how long to wait before reopening an input that ended or failed
+const REOPEN_INTERVAL: Duration = Duration::from_secs(1);
+
+// Where media is read from: stdin (-), a file or FIFO that is reopened when it ends,
+// or a unix socket (unix:PATH) the encoder connects to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    Stdin,
+    File(PathBuf),
+    Socket(PathBuf),
+}
+
+impl FromStr for Input {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "" => anyhow::bail!("missing input"),
+            "-" => Input::Stdin,
+            _ => match s.strip_prefix("unix:") {
+                Some(path) => Input::Socket(PathBuf::from(path)),
+                None => Input::File(PathBuf::from(s)),
+            },
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Input {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Input::Stdin => write!(f, "stdin"),
+            Input::File(path) => write!(f, "{}", path.display()),
+            Input::Socket(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// A rendition to ingest, given as NAME[@KBPS][=INPUT].
+// Renditions without an input are read from stdin, where each item is prefixed
+// with the index of its rendition (in the order the renditions are given).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RenditionArgs {
+    pub name: String,
+    pub bitrate: Option<u32>,
+    pub input: Option<Input>,
+}
+
+impl FromStr for RenditionArgs {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (rendition, input) = match s.split_once('=') {
+            Some((rendition, input)) => (rendition, Some(input.parse()?)),
+            None => (s, None),
+        };
+        let (name, bitrate) = match rendition.split_once('@') {
+            Some((name, bitrate)) => (name, Some(bitrate.parse().context("invalid bitrate")?)),
+            None => (rendition, None),
+        };
+        if name.is_empty() {
+            anyhow::bail!("missing rendition name");
+        }
+
+        Ok(Self {
+            name: name.to_string(),
+            bitrate,
+            input,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for RenditionArgs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+// Creates the renditions of the broadcast using the given mapping, publishes the catalog
+// and streams the renditions. Inputs that end or fail are reopened, so the broadcast
+// stays available while the encoder restarts.
+// Without any renditions, a single rendition is read from the input and the track names are kept as is.
+pub async fn run<T, F>(
+    mut broadcast: Broadcast,
+    input: Input,
+    config: BufferConfig,
+    renditions: &[RenditionArgs],
+    mapping: F,
+) -> anyhow::Result<()>
+where
+    T: VideoStreamer,
+    F: Fn(Rendition<'_>) -> anyhow::Result<T>,
+{
+    if renditions.is_empty() {
+        let video = mapping(broadcast.rendition(None, None))?;
+        broadcast.publish_catalog()?;
+        let stdin = stdin(&input);
+        let timeline = BroadcastTimeline::default();
+        return supervise(input, false, vec![video], config, stdin, timeline).await;
+    }
+
+    let mut inputs: FuturesUnordered<LocalBoxFuture<'_, anyhow::Result<()>>> =
+        FuturesUnordered::new();
+    let mut interleaved = Vec::new();
+    // the renditions are rebased together, so they stay aligned
+    let timeline = BroadcastTimeline::default();
+
+    for rendition in renditions {
+        let video = mapping(broadcast.rendition(Some(rendition.name.clone()), rendition.bitrate))?;
+        match &rendition.input {
+            Some(input) => {
+                let supervised = supervise(
+                    input.clone(),
+                    false,
+                    vec![video],
+                    config,
+                    stdin(input),
+                    timeline.clone(),
+                );
+                inputs.push(supervised.boxed_local())
+            }
+            None => interleaved.push(video),
+        }
+    }
+    if !interleaved.is_empty() {
+        let stdin = stdin(&input);
+        let supervised = supervise(input, true, interleaved, config, stdin, timeline);
+        inputs.push(supervised.boxed_local());
+    }
+
+    broadcast.publish_catalog()?;
+
+    while inputs.try_next().await?.is_some() {}
+    Ok(())
+}
+
+type Reader = Box<dyn AsyncRead + Unpin>;
+
+fn stdin(input: &Input) -> Option<Reader> {
+    match input {
+        Input::Stdin => Some(Box::new(tokio::io::stdin())),
+        _ => None,
+    }
+}
+
+// Reads an input until it ends or fails, then resynchronizes the renditions and reopens it.
+// Items of indexed inputs are prefixed with the index of their rendition.
+// The items are buffered and released to the mappings, in real time if pacing is enabled.
+// Stdin can only be read once, after it ended the broadcast stays registered without media.
+async fn supervise<T: VideoStreamer>(
+    input: Input,
+    indexed: bool,
+    mut videos: Vec<T>,
+    config: BufferConfig,
+    mut stdin: Option<Reader>,
+    timeline: BroadcastTimeline,
+) -> anyhow::Result<()> {
+    let mut resyncs: Vec<Resync> = videos
+        .iter()
+        .map(|_| Resync::new(timeline.clone()))
+        .collect();
+    let mut pacer = Pacer::new(videos.len(), config.pacing);
+    let mut listener = None;
+
+    loop {
+        let reader: anyhow::Result<Reader> = match &input {
+            Input::Stdin => match stdin.take() {
+                Some(stdin) => Ok(stdin),
+                None => {
+                    log::warn!("stdin can't be reopened, the broadcast stays idle");
+                    return std::future::pending().await;
+                }
+            },
+            Input::File(path) => open(path)
+                .await
+                .with_context(|| format!("failed to open {}", path.display())),
+            Input::Socket(path) => accept(path, &mut listener)
+                .await
+                .map(|socket| Box::new(socket) as _),
+        };
+
+        match reader {
+            Ok(mut reader) => {
+                log::info!("reading media from {}", input);
+                let buffer = IngestBuffer::new(config);
+                let reading = async {
+                    let res = read_items(&mut reader, indexed, &mut resyncs, &buffer).await;
+                    buffer.close();
+                    res
+                };
+                let releasing = async {
+                    let res = release(&buffer, &mut videos, &mut pacer, &input).await;
+                    buffer.close();
+                    res
+                };
+                let (read, released) = tokio::join!(reading, releasing);
+                match released.and(read) {
+                    Ok(()) => log::warn!("input {} ended", input),
+                    Err(err) => log::warn!("failed to ingest {}: {:#}", input, err),
+                }
+            }
+            Err(err) => log::warn!("failed to open input {}: {:#}", input, err),
+        }
+
+        for resync in &mut resyncs {
+            resync.reset();
+        }
+        tokio::time::sleep(REOPEN_INTERVAL).await;
+    }
+}
+
+// Opens a file or FIFO. Opening a FIFO blocks until there is a writer, so it is opened
+// without blocking and reading waits for the writer instead.
+async fn open(path: &Path) -> anyhow::Result<Reader> {
+    let metadata = tokio::fs::metadata(path).await?;
+    if metadata.file_type().is_fifo() {
+        return Ok(Box::new(pipe::OpenOptions::new().open_receiver(path)?));
+    }
+    Ok(Box::new(tokio::fs::File::open(path).await?))
+}
+
+// accepts the next connection of the encoder, binding the socket on first use
+async fn accept(path: &Path, listener: &mut Option<UnixListener>) -> anyhow::Result<UnixStream> {
+    let listener = match listener {
+        Some(listener) => listener,
+        None => {
+            // remove the socket of a previous run
+            tokio::fs::remove_file(path).await.ok();
+            let bound = UnixListener::bind(path)
+                .with_context(|| format!("failed to bind {}", path.display()))?;
+            listener.insert(bound)
+        }
+    };
+    let (socket, _) = listener.accept().await?;
+    Ok(socket)
+}
+
+// Reads the items of an input until it ends and buffers the ones to be released.
+// After corrupt data was skipped, frames are dropped until the next keyframe.
+async fn read_items(
+    input: &mut (impl AsyncRead + Unpin),
+    indexed: bool,
+    resyncs: &mut [Resync],
+    buffer: &IngestBuffer,
+) -> anyhow::Result<()> {
+    let mut parser = ItemParser::new(indexed, buffer.config().max_item_size);
+    let mut buf = BytesMut::new();
+    loop {
+        let read = input
+            .read_buf(&mut buf)
+            .await
+            .context("failed to read input")?;
+        if read == 0 {
+            return Ok(());
+        }
+
+        loop {
+            let (index, item) = match parser.next(&mut buf).context("failed to parse media")? {
+                Next::Incomplete => break,
+                Next::Item { index, item } => (index, item),
+                Next::Skipped(bytes) => {
+                    log::warn!(
+                        "skipped {} bytes of corrupt input, {} in total",
+                        bytes,
+                        parser.skipped_bytes
+                    );
+                    for resync in resyncs.iter_mut() {
+                        resync.skip_to_keyframe();
+                    }
+                    continue;
+                }
+            };
+
+            let resync = resyncs
+                .get_mut(index)
+                .ok_or_else(|| anyhow::anyhow!("Unknown rendition index {}", index))?;
+            if let Some(item) = resync.prepare(item)? {
+                let media_time = resync.timeline.media_time(&item);
+                buffer
+                    .push(Buffered {
+                        index,
+                        item,
+                        media_time,
+                    })
+                    .await?;
+            }
+        }
+    }
+}
+
+// hands the buffered items to the mappings in real time
+async fn release<T: VideoStreamer>(
+    buffer: &IngestBuffer,
+    videos: &mut [T],
+    pacer: &mut Pacer,
+    input: &Input,
+) -> anyhow::Result<()> {
+    while let Some(mut buffered) = buffer.pop().await {
+        pacer.wait(&buffered).await;
+        videos[buffered.index]
+            .stream(&mut buffered.item)
+            .context("failed to parse media")?;
+        if pacer.log_due() {
+            buffer.log(&input.to_string());
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SyncState {
+    AwaitingInit,
+    AwaitingKeyframe,
+    Synced,
+}
+
+// Prepares the items of a rendition for its mapping. After the input was (re)opened, items are
+// dropped until an init segment and a keyframe arrived. After a restart, the keyframe is
+// marked as a discontinuity, so players reset their decoder.
+// The timestamps are rebased, so they stay monotonic across restarts and loops of the source.
+struct Resync {
+    state: SyncState,
+    // whether the input restarted since the last keyframe
+    discontinuity: bool,
+    // frames dropped since the input was last synchronized, and in total
+    dropped: u64,
+    dropped_total: u64,
+    timeline: Timeline,
+}
+
+impl Resync {
+    fn new(timeline: BroadcastTimeline) -> Self {
+        Self {
+            state: SyncState::AwaitingInit,
+            discontinuity: false,
+            dropped: 0,
+            dropped_total: 0,
+            timeline: Timeline::new(timeline),
+        }
+    }
+
+    // drops the frames until the next keyframe, e.g. after corrupt data
+    fn skip_to_keyframe(&mut self) {
+        if self.state == SyncState::Synced {
+            self.state = SyncState::AwaitingKeyframe;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.discontinuity |= self.state != SyncState::AwaitingInit;
+        self.state = SyncState::AwaitingInit;
+    }
+
+    // item is a single init segment (0x00) or frame (0x01, keyframe, frame type, ...)
+    // returns None if the item is dropped
+    fn prepare(&mut self, mut item: BytesMut) -> anyhow::Result<Option<BytesMut>> {
+        let keyframe = item[0] == 0x01 && item[1] != 0;
+        match (item[0], self.state) {
+            (0x00, _) => {
+                self.timeline.init(&item[5..]);
+                self.state = SyncState::AwaitingKeyframe;
+            }
+            (_, SyncState::AwaitingKeyframe) if keyframe => {
+                if self.discontinuity {
+                    item[2] |= DISCONTINUITY_FLAG;
+                    self.discontinuity = false;
+                }
+                log::info!(
+                    "resynchronized input, dropped {} frames, {} in total",
+                    self.dropped,
+                    self.dropped_total
+                );
+                self.state = SyncState::Synced;
+                self.dropped = 0;
+            }
+            (_, SyncState::Synced) => {}
+            _ => {
+                self.dropped += 1;
+                self.dropped_total += 1;
+                return Ok(None);
+            }
+        }
+        if item[0] == 0x01 && self.timeline.rebase(&mut item)? {
+            item[2] |= DISCONTINUITY_FLAG;
+        }
+        Ok(Some(item))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use moq_transport::serve::Tracks;
+
+    use super::*;
+    use crate::{
+        buffer::OverloadPolicy,
+        local::{BroadcastSettings, Locals},
+        mappings::StreamPerTrack,
+    };
+
+    #[tokio::test]
+    async fn broadcast_stays_registered_after_stdin_ended() {
+        let mut locals = Locals::new();
+        let (writer, _, reader) = Tracks::new("test".to_string()).produce();
+        let registration = locals
+            .register(reader, BroadcastSettings::default())
+            .await
+            .unwrap();
+
+        let mut broadcast = Broadcast::new(writer);
+        let video = StreamPerTrack::new(broadcast.rendition(None, None)).unwrap();
+        let config = BufferConfig {
+            max_item_size: 1024,
+            capacity: 4096,
+            policy: OverloadPolicy::Block,
+            pacing: false,
+        };
+        // an init segment, then EOF
+        let stdin: Reader = Box::new(&[0x00, 0, 0, 0, 2, b'a', b'b'][..]);
+        // like a broadcast task, which unregisters the broadcast when the ingest ends
+        let ingest = async move {
+            let _registration = registration;
+            let stdin = Some(stdin);
+            supervise(
+                Input::Stdin,
+                false,
+                vec![video],
+                config,
+                stdin,
+                Default::default(),
+            )
+            .await
+        };
+        tokio::pin!(ingest);
+
+        tokio::select! {
+            res = &mut ingest => panic!("ingest ended: {:?}", res),
+            _ = tokio::time::sleep(REOPEN_INTERVAL * 2) => {}
+        }
+        assert_eq!(locals.namespaces(), vec!["test".to_string()]);
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    net::unix::pipe,
+    sync::mpsc,
+};
+
+// how long to wait before reopening a FIFO that has no reader yet
+const REOPEN_INTERVAL: Duration = Duration::from_millis(100);
+// returned when opening a FIFO for writing without a reader (the same on Linux and BSDs)
+const ENXIO: i32 = 6;
+
+// Keyframe (IDR) requests are forwarded to the ingest process as lines written to a FIFO
+// or unix socket, either "keyframe" or "keyframe <rendition>" when renditions are used.
+// Requests for the same rendition are rate limited, so joining subscribers don't shorten
+// the GOP for everyone.
+#[derive(Clone)]
+pub struct KeyframeRequests {
+    sender: mpsc::UnboundedSender<Option<String>>,
+    // the rendition keyframes are requested for, all renditions if None
+    rendition: Option<String>,
+}
+
+impl KeyframeRequests {
+    pub fn new(path: PathBuf, interval: Duration) -> (Self, KeyframeRequester) {
+        let (sender, requests) = mpsc::unbounded_channel();
+        let requester = KeyframeRequester {
+            path,
+            interval,
+            requests,
+        };
+        let requests = Self {
+            sender,
+            rendition: None,
+        };
+        (requests, requester)
+    }
+
+    // requests keyframes for a rendition named in the broadcast's catalog
+    pub fn for_rendition(&self, rendition: Option<String>) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            rendition,
+        }
+    }
+
+    pub fn request(&self) {
+        self.sender.send(self.rendition.clone()).ok();
+    }
+}
+
+pub struct KeyframeRequester {
+    path: PathBuf,
+    interval: Duration,
+    requests: mpsc::UnboundedReceiver<Option<String>>,
+}
+
+impl KeyframeRequester {
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        let mut output: Option<Box<dyn AsyncWrite + Unpin + Send>> = None;
+        let mut requested: HashMap<Option<String>, Instant> = HashMap::new();
+
+        while let Some(rendition) = self.requests.recv().await {
+            let now = Instant::now();
+            if let Some(last) = requested.get(&rendition) {
+                if now.duration_since(*last) < self.interval {
+                    continue;
+                }
+            }
+            requested.insert(rendition.clone(), now);
+
+            let line = match &rendition {
+                Some(rendition) => format!("keyframe {}\n", rendition),
+                None => "keyframe\n".to_string(),
+            };
+
+            // (re)open the output lazily, so the ingest process can be restarted
+            if output.is_none() {
+                match self.open().await {
+                    Ok(opened) => output = Some(opened),
+                    Err(err) => {
+                        log::warn!("failed to open keyframe request output: {}", err);
+                        continue;
+                    }
+                }
+            }
+            let writer = output.as_mut().unwrap();
+            if let Err(err) = writer.write_all(line.as_bytes()).await {
+                log::warn!("failed to request keyframe: {}", err);
+                output = None;
+                continue;
+            }
+            writer.flush().await.ok();
+            log::info!("requested keyframe: {:?}", rendition);
+        }
+
+        Ok(())
+    }
+
+    async fn open(&self) -> anyhow::Result<Box<dyn AsyncWrite + Unpin + Send>> {
+        let metadata = tokio::fs::metadata(&self.path)
+            .await
+            .with_context(|| format!("missing {}", self.path.display()))?;
+
+        if metadata.file_type().is_socket() {
+            let socket = tokio::net::UnixStream::connect(&self.path).await?;
+            return Ok(Box::new(socket));
+        }
+
+        if !metadata.file_type().is_fifo() {
+            let file = tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(&self.path)
+                .await?;
+            return Ok(Box::new(file));
+        }
+
+        // a FIFO is opened without blocking, which fails until the ingest process opened it
+        let mut warned = false;
+        loop {
+            match pipe::OpenOptions::new().open_sender(&self.path) {
+                Ok(sender) => return Ok(Box::new(sender)),
+                Err(err) if err.raw_os_error() == Some(ENXIO) => {
+                    if !warned {
+                        log::warn!("waiting for a reader of {}", self.path.display());
+                        warned = true;
+                    }
+                    tokio::time::sleep(REOPEN_INTERVAL).await;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncBufReadExt;
+
+    use super::*;
+
+    fn fifo(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let created = std::process::Command::new("mkfifo").arg(&path).status();
+        assert!(created.unwrap().success());
+        path
+    }
+
+    #[tokio::test]
+    async fn waits_for_reader_of_fifo() {
+        let path = fifo("keyframe-requests");
+        let (requests, requester) = KeyframeRequests::new(path.clone(), Duration::ZERO);
+        let running = tokio::spawn(requester.run());
+
+        // requested before the FIFO has a reader
+        requests.for_rendition(Some("720p".to_string())).request();
+        tokio::time::sleep(REOPEN_INTERVAL * 2).await;
+
+        let receiver = pipe::OpenOptions::new().open_receiver(&path).unwrap();
+        let mut lines = tokio::io::BufReader::new(receiver).lines();
+        let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line()).await;
+        assert_eq!(line.unwrap().unwrap().as_deref(), Some("keyframe 720p"));
+
+        // the requester doesn't block a thread while waiting, so it ends with the runtime
+        running.abort();
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,27 @@
+pub mod abr;
+pub mod admin;
+pub mod broadcast;
+pub mod broadcasts;
+pub mod buffer;
+pub mod cache;
+pub mod config;
+pub mod congestion;
+pub mod deadline;
+pub mod egress;
+pub mod forward;
+pub mod framing;
+pub mod impair;
+pub mod ingest;
+pub mod keyframe;
+pub mod limits;
+pub mod listener;
+pub mod local;
+pub mod mappings;
+pub mod qlog;
+pub mod sendlog;
+pub mod server;
+pub mod sessions;
+pub mod shutdown;
+pub mod stats;
+pub mod timeline;
+pub mod video;
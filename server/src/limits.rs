@@ -0,0 +1,152 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::{AuthConfig, Limits};
+
+// QUIC application error codes (MoQ termination codes) used to close sessions,
+// when the MoQ setup wasn't completed in time
+pub const PROTOCOL_VIOLATION_CODE: u32 = 0x3;
+// the session was idle (MoQ "no error")
+pub const IDLE_CODE: u32 = 0x0;
+
+// The code of the SUBSCRIBE_ERROR sent for subscriptions beyond the limit,
+// in the style of moq-transport's HTTP-like error codes (404, 409, 500).
+pub const TOO_MANY_SUBSCRIPTIONS_CODE: u64 = 429;
+
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+// Why a connection was rejected, it is refused with QUIC's CONNECTION_REFUSED error.
+pub struct Rejection {
+    pub reason: &'static str,
+}
+
+// Decides which connections are accepted as sessions.
+pub struct Admission {
+    auth: AuthConfig,
+    // permits for the sessions, if limited
+    permits: Option<Arc<Semaphore>>,
+    rate: Option<Mutex<ConnectionRate>>,
+}
+
+impl Admission {
+    pub fn new(auth: AuthConfig, limits: &Limits) -> Self {
+        Self {
+            auth,
+            permits: limits.max_sessions.map(|max| Arc::new(Semaphore::new(max))),
+            rate: limits
+                .max_connections_per_ip
+                .map(|max| Mutex::new(ConnectionRate::new(max))),
+        }
+    }
+
+    // Returns the session's permit, released once the session ended,
+    // or why the connection should be refused.
+    pub fn admit(&self, addr: IpAddr) -> Result<Option<OwnedSemaphorePermit>, Rejection> {
+        if !self.auth.allows(addr) {
+            return Err(Rejection {
+                reason: "unauthorized",
+            });
+        }
+
+        if let Some(rate) = &self.rate {
+            if !rate.lock().unwrap().allow(addr, Instant::now()) {
+                return Err(Rejection {
+                    reason: "too many connections",
+                });
+            }
+        }
+
+        match &self.permits {
+            Some(permits) => match permits.clone().try_acquire_owned() {
+                Ok(permit) => Ok(Some(permit)),
+                Err(_) => Err(Rejection {
+                    reason: "too many sessions",
+                }),
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+// The connections of each address over the last minute.
+struct ConnectionRate {
+    max: u32,
+    recent: HashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl ConnectionRate {
+    fn new(max: u32) -> Self {
+        Self {
+            max,
+            recent: HashMap::new(),
+        }
+    }
+
+    // rejected connections count as well, so clients retrying right away stay limited
+    fn allow(&mut self, addr: IpAddr, now: Instant) -> bool {
+        self.recent.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|time| now.duration_since(*time) > RATE_WINDOW)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        let times = self.recent.entry(addr.to_canonical()).or_default();
+        times.push_back(now);
+        times.len() <= self.max as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    #[test]
+    fn connection_rate_limits_each_address() {
+        let mut rate = ConnectionRate::new(2);
+        let now = Instant::now();
+        let a = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let b = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        assert!(rate.allow(a, now));
+        assert!(rate.allow(a, now));
+        assert!(!rate.allow(a, now));
+        assert!(rate.allow(b, now));
+    }
+
+    #[test]
+    fn connection_rate_forgets_old_connections() {
+        let mut rate = ConnectionRate::new(1);
+        let now = Instant::now();
+        let addr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        assert!(rate.allow(addr, now));
+        assert!(!rate.allow(addr, now + RATE_WINDOW / 2));
+        // the rejected connection counts as well
+        assert!(!rate.allow(addr, now + RATE_WINDOW + Duration::from_secs(1)));
+        assert!(rate.allow(addr, now + 2 * RATE_WINDOW + Duration::from_secs(2)));
+        assert_eq!(rate.recent.len(), 1);
+    }
+
+    #[test]
+    fn connection_rate_maps_ipv4_mapped_addresses() {
+        let mut rate = ConnectionRate::new(1);
+        let now = Instant::now();
+        let v4 = Ipv4Addr::new(10, 0, 0, 1);
+
+        assert!(rate.allow(IpAddr::V4(v4), now));
+        assert!(!rate.allow(IpAddr::V6(v4.to_ipv6_mapped()), now));
+        assert!(rate.allow(IpAddr::V6(Ipv6Addr::LOCALHOST), now));
+    }
+}
@@ -0,0 +1,295 @@
+use std::{net, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use futures::{future::BoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use tokio::sync::OwnedSemaphorePermit;
+
+use crate::{config::Limits, limits::Admission};
+
+// https://github.com/kixelated/moq-rs/blob/main/moq-native/src/quic.rs
+
+// Accepts WebTransport and raw MoQ sessions like moq-native's server, but also returns
+// the QUIC connection of each session, which moq-native's session wrapper hides.
+// The connection is needed for the congestion statistics and qlog traces.
+// Connections are admitted before their handshake, and the number and duration
+// of the handshakes in progress are limited.
+pub struct Listener {
+    quic: quinn::Endpoint,
+    admission: Arc<Admission>,
+    handshake_timeout: Duration,
+    max_handshakes: usize,
+    accept: FuturesUnordered<BoxFuture<'static, anyhow::Result<Accepted>>>,
+}
+
+pub struct Accepted {
+    pub connection: quinn::Connection,
+    pub session: web_transport::Session,
+    // released once the session ended
+    pub permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Listener {
+    pub fn bind(
+        bind: net::SocketAddr,
+        tls: &moq_native::tls::Config,
+        admission: Arc<Admission>,
+        limits: &Limits,
+    ) -> anyhow::Result<Self> {
+        let mut config = tls.server.clone().context("missing TLS certificate")?;
+        config.alpn_protocols = vec![
+            web_transport_quinn::ALPN.to_vec(),
+            moq_transport::setup::ALPN.to_vec(),
+        ];
+        config.key_log = Arc::new(quinn::rustls::KeyLogFile::new());
+
+        // the same transport settings as moq-native, with BBR congestion control
+        let mut transport = quinn::TransportConfig::default();
+        transport.max_idle_timeout(Some(Duration::from_secs(10).try_into().unwrap()));
+        transport.keep_alive_interval(Some(Duration::from_secs(4)));
+        transport.congestion_controller_factory(Arc::new(quinn::congestion::BbrConfig::default()));
+        transport.mtu_discovery_config(None);
+
+        let config: quinn::crypto::rustls::QuicServerConfig = config.try_into()?;
+        let mut config = quinn::ServerConfig::with_crypto(Arc::new(config));
+        config.transport_config(Arc::new(transport));
+
+        let runtime = quinn::default_runtime().context("no async runtime")?;
+        let socket = std::net::UdpSocket::bind(bind).context("failed to bind UDP socket")?;
+        let quic = quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            Some(config),
+            socket,
+            runtime,
+        )
+        .context("failed to create QUIC endpoint")?;
+
+        Ok(Self {
+            quic,
+            admission,
+            handshake_timeout: Duration::from_millis(limits.handshake_timeout),
+            max_handshakes: limits.max_handshakes,
+            accept: Default::default(),
+        })
+    }
+
+    // returns None once the endpoint is closed
+    pub async fn accept(&mut self) -> Option<Accepted> {
+        loop {
+            tokio::select! {
+                res = self.quic.accept() => self.handshake(res?),
+                res = self.accept.next(), if !self.accept.is_empty() => {
+                    match res.unwrap() {
+                        Ok(accepted) => return Some(accepted),
+                        Err(err) => log::warn!("failed to accept QUIC connection: {:#}", err),
+                    }
+                }
+            }
+        }
+    }
+
+    // starts the handshake of an admitted connection, others are refused before any state
+    // is kept for them, and ignored while too many handshakes are in progress
+    fn handshake(&mut self, incoming: quinn::Incoming) {
+        let remote = incoming.remote_address();
+        if self.accept.len() >= self.max_handshakes {
+            log::warn!("ignored connection from {}: too many handshakes", remote);
+            incoming.ignore();
+            return;
+        }
+
+        let permit = match self.admission.admit(remote.ip()) {
+            Ok(permit) => permit,
+            Err(rejection) => {
+                log::warn!("refused connection from {}: {}", remote, rejection.reason);
+                incoming.refuse();
+                return;
+            }
+        };
+
+        let timeout = self.handshake_timeout;
+        self.accept.push(
+            async move {
+                let accepting = tokio::time::timeout(timeout, accept_session(incoming, permit));
+                accepting
+                    .await
+                    .with_context(|| format!("handshake with {} timed out", remote))?
+            }
+            .boxed(),
+        );
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<net::SocketAddr> {
+        self.quic
+            .local_addr()
+            .context("failed to get local address")
+    }
+}
+
+// the connection is closed if the handshake fails or the future is dropped
+async fn accept_session(
+    conn: quinn::Incoming,
+    permit: Option<OwnedSemaphorePermit>,
+) -> anyhow::Result<Accepted> {
+    let mut conn = conn.accept()?;
+
+    let handshake = conn
+        .handshake_data()
+        .await?
+        .downcast::<quinn::crypto::rustls::HandshakeData>()
+        .map_err(|_| anyhow::anyhow!("Unexpected handshake data"))?;
+    let alpn = handshake.protocol.context("missing ALPN")?;
+
+    let connection = conn.await.context("failed to establish QUIC connection")?;
+    log::debug!(
+        "established QUIC connection: id={} ip={} alpn={}",
+        connection.stable_id(),
+        connection.remote_address(),
+        String::from_utf8_lossy(&alpn),
+    );
+
+    let session = match alpn.as_slice() {
+        web_transport_quinn::ALPN => {
+            let request = web_transport_quinn::accept(connection.clone())
+                .await
+                .context("failed to receive WebTransport request")?;
+            request
+                .ok()
+                .await
+                .context("failed to respond to WebTransport request")?
+        }
+        // raw QUIC, pretending to be a WebTransport session
+        moq_transport::setup::ALPN => connection.clone().into(),
+        _ => anyhow::bail!("unsupported ALPN: {}", String::from_utf8_lossy(&alpn)),
+    };
+
+    Ok(Accepted {
+        connection,
+        session: session.into(),
+        permit,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::config::AuthConfig;
+
+    fn tls() -> moq_native::tls::Config {
+        let testdata = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let args = moq_native::tls::Args {
+            cert: vec![testdata.join("cert.pem")],
+            key: vec![testdata.join("key.pem")],
+            // the self-signed certificate isn't verified, but avoids loading the system roots
+            root: vec![testdata.join("cert.pem")],
+            disable_verify: true,
+        };
+        args.load().unwrap()
+    }
+
+    // accepts sessions on localhost in the background
+    fn listen(auth: AuthConfig, limits: Limits) -> (net::SocketAddr, mpsc::Receiver<Accepted>) {
+        let admission = Arc::new(Admission::new(auth, &limits));
+        let bind = "127.0.0.1:0".parse().unwrap();
+        let mut listener = Listener::bind(bind, &tls(), admission, &limits).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let (sender, accepted) = mpsc::channel(16);
+        tokio::spawn(async move {
+            while let Some(session) = listener.accept().await {
+                sender.send(session).await.ok();
+            }
+        });
+        (addr, accepted)
+    }
+
+    async fn connect(
+        server: net::SocketAddr,
+        alpn: &[u8],
+    ) -> Result<quinn::Connection, quinn::ConnectionError> {
+        let mut config = tls().client;
+        config.alpn_protocols = vec![alpn.to_vec()];
+        let config: quinn::crypto::rustls::QuicClientConfig = config.try_into().unwrap();
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(config)));
+        endpoint.connect(server, "localhost").unwrap().await
+    }
+
+    // QUIC's CONNECTION_REFUSED transport error
+    const CONNECTION_REFUSED: u64 = 0x2;
+
+    fn refused(res: Result<quinn::Connection, quinn::ConnectionError>) -> bool {
+        matches!(
+            res,
+            Err(quinn::ConnectionError::ConnectionClosed(close))
+                if u64::from(close.error_code) == CONNECTION_REFUSED
+        )
+    }
+
+    const MOQ: &[u8] = moq_transport::setup::ALPN;
+    const WEBTRANSPORT: &[u8] = web_transport_quinn::ALPN;
+
+    #[tokio::test]
+    async fn refuses_unauthorized_before_handshake() {
+        let auth = AuthConfig {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        let (server, _) = listen(auth, Limits::default());
+        assert!(refused(connect(server, MOQ).await));
+    }
+
+    #[tokio::test]
+    async fn refuses_connections_beyond_rate_and_session_limits() {
+        let limits = Limits {
+            max_connections_per_ip: Some(2),
+            max_sessions: Some(1),
+            ..Default::default()
+        };
+        let (server, mut accepted) = listen(AuthConfig::default(), limits);
+
+        let _first = connect(server, MOQ).await.unwrap();
+        let session = accepted.recv().await.unwrap();
+        assert!(session.permit.is_some());
+
+        // the session holds the only permit
+        assert!(refused(connect(server, MOQ).await));
+        drop(session);
+        // the permit is free again, but the address made too many connections
+        assert!(refused(connect(server, MOQ).await));
+    }
+
+    #[tokio::test]
+    async fn ignores_connections_beyond_max_handshakes() {
+        let limits = Limits {
+            max_handshakes: 1,
+            ..Default::default()
+        };
+        let (server, _) = listen(AuthConfig::default(), limits);
+
+        // the WebTransport handshake isn't completed without a request
+        let _pending = connect(server, WEBTRANSPORT).await.unwrap();
+        let ignored = tokio::time::timeout(Duration::from_millis(500), connect(server, MOQ));
+        assert!(ignored.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn closes_connections_after_handshake_timeout() {
+        let limits = Limits {
+            handshake_timeout: 100,
+            max_handshakes: 1,
+            ..Default::default()
+        };
+        let (server, mut accepted) = listen(AuthConfig::default(), limits);
+
+        let pending = connect(server, WEBTRANSPORT).await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(5), pending.closed());
+        assert!(closed.await.is_ok());
+
+        // which frees its place for the next handshake
+        let _connection = connect(server, MOQ).await.unwrap();
+        assert!(accepted.recv().await.is_some());
+    }
+}
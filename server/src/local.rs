@@ -1,13 +1,43 @@
+use anyhow::Context;
 use moq_transport::serve::{ServeError, TracksReader};
 use std::collections::hash_map;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use crate::{
+    broadcast::read_catalog, cache::GopCache, keyframe::KeyframeRequests, server::ForwardConfig,
+};
 
 // https://github.com/kixelated/moq-rs/blob/main/moq-relay/src/local.rs
 
+// How the tracks of a broadcast are served.
+#[derive(Clone, Default)]
+pub struct BroadcastSettings {
+    pub forward: ForwardConfig,
+    // request keyframes from the broadcast's encoder when subscribers join or lose data
+    pub keyframes: Option<KeyframeRequests>,
+}
+
+// A registered broadcast. The generation tells a restarted broadcast apart from the previous one,
+// so the previous one's registration doesn't remove the restarted one when it's dropped late.
+struct Registered {
+    generation: u64,
+    tracks: TracksReader,
+    settings: BroadcastSettings,
+}
+
+// the GOP caches of the media tracks, by namespace and track name, with the generation
+// of the broadcast they belong to
+type Caches = HashMap<(String, String), (u64, GopCache)>;
+
 #[derive(Clone)]
 pub struct Locals {
-    lookup: Arc<Mutex<HashMap<String, TracksReader>>>,
+    lookup: Arc<Mutex<HashMap<String, Registered>>>,
+    caches: Arc<Mutex<Caches>>,
+    generations: Arc<AtomicU64>,
 }
 
 impl Default for Locals {
@@ -20,36 +50,176 @@ impl Locals {
     pub fn new() -> Self {
         Self {
             lookup: Default::default(),
+            caches: Default::default(),
+            generations: Default::default(),
         }
     }
 
-    pub async fn register(&mut self, tracks: TracksReader) -> anyhow::Result<Registration> {
+    pub async fn register(
+        &mut self,
+        tracks: TracksReader,
+        settings: BroadcastSettings,
+    ) -> anyhow::Result<Registration> {
         let namespace = tracks.namespace.clone();
+        let generation = self.generations.fetch_add(1, Ordering::Relaxed);
         match self.lookup.lock().unwrap().entry(namespace.clone()) {
-            hash_map::Entry::Vacant(entry) => entry.insert(tracks),
+            hash_map::Entry::Vacant(entry) => entry.insert(Registered {
+                generation,
+                tracks: tracks.clone(),
+                settings,
+            }),
             hash_map::Entry::Occupied(_) => return Err(ServeError::Duplicate.into()),
         };
 
+        // cache the current GOP of every media track listed in the catalog
+        let locals = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = locals.tap(tracks, generation).await {
+                log::warn!("failed to cache broadcast: {}", err);
+            }
+        });
+
         let registration = Registration {
             locals: self.clone(),
             namespace,
+            generation,
         };
 
         Ok(registration)
     }
 
     pub fn route(&self, namespace: &str) -> Option<TracksReader> {
-        self.lookup.lock().unwrap().get(namespace).cloned()
+        let lookup = self.lookup.lock().unwrap();
+        lookup
+            .get(namespace)
+            .map(|registered| registered.tracks.clone())
+    }
+
+    pub fn settings(&self, namespace: &str) -> Option<BroadcastSettings> {
+        let lookup = self.lookup.lock().unwrap();
+        lookup
+            .get(namespace)
+            .map(|registered| registered.settings.clone())
+    }
+
+    // keyframe requests for the rendition of a track, None if they are disabled
+    pub async fn keyframes(
+        &self,
+        namespace: &str,
+        name: &str,
+    ) -> anyhow::Result<Option<KeyframeRequests>> {
+        let Some(keyframes) = self
+            .settings(namespace)
+            .and_then(|settings| settings.keyframes)
+        else {
+            return Ok(None);
+        };
+        let mut tracks = self.route(namespace).context("missing broadcast")?;
+        let catalog = read_catalog(&mut tracks).await?;
+        let rendition = catalog
+            .renditions
+            .into_iter()
+            .find(|rendition| rendition.tracks.iter().any(|track| track == name))
+            .context("track not in catalog")?;
+        Ok(Some(keyframes.for_rendition(rendition.name)))
+    }
+
+    pub fn cache(&self, namespace: &str, name: &str) -> Option<GopCache> {
+        let key = (namespace.to_string(), name.to_string());
+        let caches = self.caches.lock().unwrap();
+        caches.get(&key).map(|(_, cache)| cache.clone())
+    }
+
+    pub fn namespaces(&self) -> Vec<String> {
+        self.lookup.lock().unwrap().keys().cloned().collect()
+    }
+
+    // the cached tracks of a broadcast, by name
+    pub fn caches(&self, namespace: &str) -> Vec<(String, GopCache)> {
+        let caches = self.caches.lock().unwrap();
+        caches
+            .iter()
+            .filter(|((ns, _), _)| ns == namespace)
+            .map(|((_, name), (_, cache))| (name.clone(), cache.clone()))
+            .collect()
+    }
+
+    async fn tap(&self, mut tracks: TracksReader, generation: u64) -> anyhow::Result<()> {
+        let catalog = read_catalog(&mut tracks).await?;
+        let names = catalog
+            .renditions
+            .into_iter()
+            .flat_map(|rendition| rendition.tracks);
+
+        for name in names.filter(|name| !name.starts_with("init")) {
+            let track = tracks
+                .subscribe(&name)
+                .ok_or_else(|| anyhow::anyhow!("Missing track {}", name))?;
+            let Some((cache, tap)) = GopCache::tap(track).await? else {
+                continue;
+            };
+
+            // the broadcast may have been stopped or restarted in the meantime
+            let registered = self
+                .lookup
+                .lock()
+                .unwrap()
+                .get(&tracks.namespace)
+                .is_some_and(|registered| registered.generation == generation);
+            if !registered {
+                return Ok(());
+            }
+
+            let key = (tracks.namespace.clone(), name.clone());
+            self.caches
+                .lock()
+                .unwrap()
+                .insert(key.clone(), (generation, cache.clone()));
+
+            let caches = self.caches.clone();
+            tokio::spawn(async move {
+                if let Err(err) = tap.run().await {
+                    log::warn!("failed to cache track {}: {}", name, err);
+                }
+                // the broadcast may have been restarted in the meantime
+                let mut caches = caches.lock().unwrap();
+                if caches
+                    .get(&key)
+                    .is_some_and(|(_, current)| current.same(&cache))
+                {
+                    caches.remove(&key);
+                }
+            });
+        }
+
+        Ok(())
     }
 }
 
 pub struct Registration {
     locals: Locals,
     namespace: String,
+    generation: u64,
 }
 
-// impl Drop for Registration {
-//     fn drop(&mut self) {
-//         self.locals.lookup.lock().unwrap().remove(&self.namespace);
-//     }
-// }
+impl Drop for Registration {
+    // only removes the broadcast and its caches if it wasn't registered again meanwhile
+    fn drop(&mut self) {
+        let mut lookup = self.locals.lookup.lock().unwrap();
+        if lookup
+            .get(&self.namespace)
+            .is_some_and(|registered| registered.generation == self.generation)
+        {
+            lookup.remove(&self.namespace);
+        }
+        drop(lookup);
+
+        self.locals
+            .caches
+            .lock()
+            .unwrap()
+            .retain(|(namespace, _), (generation, _)| {
+                namespace != &self.namespace || *generation != self.generation
+            });
+    }
+}
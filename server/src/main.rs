@@ -1,19 +1,21 @@
-mod local;
-mod mappings;
-mod server;
-mod video;
-
 use anyhow::Context;
-use bytes::BytesMut;
 use clap::Parser;
-use mappings::StreamPerFrameType;
-use moq_transport::serve::Tracks;
-use server::*;
-use std::net;
-use tokio::io::AsyncReadExt;
-use video::VideoStreamer;
-
-use crate::mappings::{StreamPerBFrame, StreamPerGop, StreamPerTrack};
+use moq_streaming_server_rs::{
+    admin::Admin,
+    broadcasts::Broadcasts,
+    config::{
+        AuthConfig, BroadcastConfig, Config, EgressConfig, ImpairConfig, Limits, LoggingConfig,
+    },
+    local::Locals,
+    server::*,
+    sessions::Sessions,
+    shutdown::{self, Drain},
+};
+use std::{net, path::PathBuf, time::Duration};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
 
 #[derive(Parser, Clone)]
 pub struct Cli {
@@ -23,10 +25,44 @@ pub struct Cli {
     /// The TLS configuration.
     #[command(flatten)]
     pub tls: moq_native::tls::Args,
+    /// Load the listeners, TLS, auth, limits and broadcasts from this TOML file
+    /// instead of the command line. Broadcasts are reloaded on SIGHUP.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Limits on the sessions, unless a config file is used.
+    #[command(flatten)]
+    pub limits: Limits,
+    /// Egress rate limits, unless a config file is used.
+    #[command(flatten)]
+    pub egress: EgressConfig,
+    /// Network impairments emulated on the listener, unless a config file is used.
+    #[command(flatten)]
+    pub impairment: ImpairConfig,
+    /// Traces written for offline analysis, unless a config file is used.
+    #[command(flatten)]
+    pub logging: LoggingConfig,
+    /// The broadcast, unless a config file is used.
+    #[command(flatten)]
+    pub broadcast: BroadcastConfig,
+    /// Serve the admin HTTP/JSON API on this address, e.g. 127.0.0.1:8080
+    #[arg(long)]
+    pub admin: Option<net::SocketAddr>,
+    /// On SIGTERM, wait this many milliseconds for the subscriptions to end before exiting
+    #[arg(long, default_value_t = 5000)]
+    pub drain_timeout: u64,
+    /// On shutdown, point subscribers at this server
+    #[arg(long)]
+    pub goaway_url: Option<String>,
+    /// On shutdown, close sessions whose subscriptions haven't ended after this many
+    /// milliseconds
+    #[arg(long, default_value_t = 2000)]
+    pub goaway_grace: u64,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+// how long to wait for blocking tasks, like reading stdin, when exiting
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(100);
+
+fn main() -> anyhow::Result<()> {
     env_logger::init();
     let tracer = tracing_subscriber::FmtSubscriber::builder()
         .with_max_level(tracing::Level::WARN)
@@ -34,41 +70,119 @@ async fn main() -> anyhow::Result<()> {
     tracing::subscriber::set_global_default(tracer).unwrap();
 
     let cli = Cli::parse();
-    let tls = cli.tls.load()?;
-    if tls.server.is_none() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    // the ingest isn't Send, so the broadcasts run on this thread
+    let res = runtime.block_on(tokio::task::LocalSet::new().run_until(run(cli)));
+    // don't wait for reads that never finish
+    runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+    res
+}
+
+async fn run(cli: Cli) -> anyhow::Result<()> {
+    let (draining, drain) = Drain::new(
+        cli.goaway_url.clone(),
+        Duration::from_millis(cli.goaway_grace),
+    );
+    let (server_config, configs, admin) = match &cli.config {
+        Some(path) => {
+            let config = Config::load(path)?;
+            let server_config = ServerConfig {
+                listeners: config
+                    .listeners
+                    .iter()
+                    .map(|listener| listener.bind)
+                    .collect(),
+                tls: config.tls.load()?,
+                auth: config.auth,
+                limits: config.limits,
+                egress: config.egress,
+                impairment: config.impairment,
+                logging: config.logging,
+                drain,
+            };
+            let admin = config.admin.map(|admin| admin.bind);
+            (server_config, config.broadcasts, admin)
+        }
+        None => {
+            let server_config = ServerConfig {
+                listeners: vec![cli.bind],
+                tls: cli.tls.load()?,
+                auth: AuthConfig::default(),
+                limits: cli.limits,
+                egress: cli.egress,
+                impairment: cli.impairment.clone(),
+                logging: cli.logging.clone(),
+                drain,
+            };
+            (server_config, vec![cli.broadcast.clone()], cli.admin)
+        }
+    };
+    if server_config.tls.server.is_none() {
         anyhow::bail!("missing TLS certificates");
     }
 
-    let namespace = Tracks::new("livestream".to_string());
-    let (tracks_writer, _, tracks_reader) = namespace.produce();
+    let locals = Locals::new();
+    let sessions = Sessions::default();
+    let server = Server::new(server_config, locals.clone(), sessions.clone()).await?;
+
+    let mut broadcasts = Broadcasts::new(locals.clone());
+    broadcasts.apply(&configs).await;
+
+    let (commands, mut received) = mpsc::channel(1);
+    let admin = async {
+        match admin {
+            Some(bind) => Admin::new(locals, sessions, commands).run(bind).await,
+            None => std::future::pending().await,
+        }
+    };
 
-    let server = Server::new(
-        ServerConfig {
-            bind: cli.bind,
-            tls: tls.clone(),
-        },
-        tracks_reader,
-    )
-    .await?;
+    let server = server.run();
+    tokio::pin!(server);
+    tokio::pin!(admin);
+    let shutdown = shutdown::signal_received();
+    tokio::pin!(shutdown);
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            res = &mut server => return res.context("session error"),
+            res = &mut admin => return res.context("admin API error"),
+            Some(command) = received.recv() => broadcasts.handle(command).await,
+            _ = hangup.recv() => reload(&cli, &mut broadcasts).await,
+            res = &mut shutdown => {
+                res?;
+                break;
+            }
+        }
+    }
 
-    let video = StreamPerTrack::new(tracks_writer)?;
+    // stopping the ingest ends the current group of every track,
+    // so the subscriptions end once the remaining objects were sent
+    log::info!("shutting down");
+    draining.send_replace(true);
+    broadcasts.stop_all().await;
 
-    tokio::select! {
-        res = server.run() => res.context("session error")?,
-        res = read_video(video) => res.context("media error")?,
+    let timeout = Duration::from_millis(cli.drain_timeout);
+    match tokio::time::timeout(timeout, server).await {
+        Ok(res) => res.context("session error")?,
+        Err(_) => log::warn!("sessions not drained after {:?}", timeout),
     }
 
     Ok(())
 }
 
-async fn read_video<T: VideoStreamer>(mut video: T) -> anyhow::Result<()> {
-    let mut input = tokio::io::stdin();
-    let mut buf = BytesMut::new();
-    loop {
-        input
-            .read_buf(&mut buf)
-            .await
-            .context("failed to read from stdin")?;
-        video.stream(&mut buf).context("failed to parse media")?;
+// Reloads the broadcasts from the config file, the other sections need a restart.
+// The running broadcasts are kept if the file is invalid.
+async fn reload(cli: &Cli, broadcasts: &mut Broadcasts) {
+    let Some(path) = &cli.config else {
+        log::warn!("received SIGHUP without a config file, ignoring");
+        return;
+    };
+
+    log::info!("reloading {}", path.display());
+    match Config::load(path) {
+        Ok(config) => broadcasts.apply(&config.broadcasts).await,
+        Err(err) => log::warn!("failed to reload config: {:#}", err),
     }
 }
@@ -1,11 +1,13 @@
+use crate::broadcast::Rendition;
 use crate::video::{
     next_item, parse_item, serialize_frame, FrameType, MediaStreamItem, VideoStreamer,
 };
 use anyhow::Result;
 use bytes::BytesMut;
-use moq_transport::serve::{
-    GroupWriter, GroupsWriter, Object, ObjectsWriter, StreamGroupWriter, TracksWriter,
-};
+use moq_transport::serve::{GroupWriter, GroupsWriter, Object, ObjectsWriter, StreamGroupWriter};
+
+// 25 bits for B-group
+const MAX_B_GROUP: u32 = 0x1FFFFFF;
 
 pub struct StreamPerBFrame {
     init_track: StreamGroupWriter,
@@ -21,7 +23,7 @@ pub struct StreamPerBFrame {
 }
 
 impl VideoStreamer for StreamPerBFrame {
-    fn new(mut namespace: TracksWriter) -> anyhow::Result<Self> {
+    fn new(mut namespace: Rendition) -> anyhow::Result<Self> {
         let init_track = namespace
             .create("init")
             .ok_or_else(|| anyhow::anyhow!("Failed to create init track"))?
@@ -68,10 +70,9 @@ impl VideoStreamer for StreamPerBFrame {
                     }
 
                     if frame.frame_type != FrameType::B {
-                        self.b_group = self
-                            .b_group
-                            .checked_add(1)
-                            .ok_or_else(|| anyhow::anyhow!("P-group counter overflow"))?;
+                        // B-groups are counted over the whole stream and wrap around
+                        // after days, so newer B-groups keep a higher priority across GOPs
+                        self.b_group = self.b_group.wrapping_add(1) & MAX_B_GROUP;
                         self.b_frame_index = 0;
                     }
 
@@ -93,16 +94,9 @@ impl VideoStreamer for StreamPerBFrame {
                         // }
                         // let priority = ((max_b_group - self.b_group) << 6) | self.b_frame_index;
                         // test if higher value <=> higher priority
-                        let max_b_group: u32 = 0x1FFFFFF; // 25 bits for B-group
-                        if self.b_group >= max_b_group {
-                            return Err(anyhow::anyhow!("Max value for P-group exceeded"));
-                        }
                         let max_b_frame_index: u32 = 0x3F; // 6 bits for B-frame index
-                        if self.b_frame_index > max_b_frame_index {
-                            return Err(anyhow::anyhow!("Max value for b-frame index exceeded"));
-                        }
-                        let priority =
-                            ((self.b_group) << 6) | max_b_frame_index - self.b_frame_index;
+                        let b_frame_index = self.b_frame_index.min(max_b_frame_index);
+                        let priority = (self.b_group << 6) | (max_b_frame_index - b_frame_index);
 
                         self.b_frames_track.write(
                             Object {
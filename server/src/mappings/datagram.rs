@@ -0,0 +1,267 @@
+use crate::broadcast::Rendition;
+use crate::video::{
+    next_item, parse_item, serialize_frame, FrameType, MediaStreamItem, VideoStreamer,
+};
+use anyhow::Result;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use moq_transport::serve::{
+    Datagram, DatagramsWriter, GroupWriter, GroupsWriter, StreamGroupWriter,
+};
+use std::collections::BTreeMap;
+
+// leaves room for the QUIC and MoQ datagram headers in a 1200 byte packet
+const MAX_DATAGRAM_PAYLOAD: usize = 1100;
+// frame id, fragment index and fragment count
+const FRAGMENT_HEADER: usize = 8 + 2 + 2;
+// incomplete frames kept while reassembling, older ones are dropped
+const MAX_INCOMPLETE_FRAMES: usize = 64;
+
+// Like StreamPerBFrame, but B-frames are sent as datagrams since they are not referenced
+// by other frames and retransmitting them is wasted bandwidth.
+// Frames that don't fit into a datagram are split into fragments, each prefixed with
+// the frame id, the index of the fragment and the number of fragments (see Reassembler).
+pub struct DatagramPerBFrame {
+    init_track: StreamGroupWriter,
+
+    video_track: GroupsWriter,
+    current: GroupWriter,
+
+    b_frames_track: DatagramsWriter,
+    group_id: u64,
+    obj_id: u64,
+
+    // P-frames up to this size (and fitting into a datagram) are sent as datagrams as well
+    p_frame_threshold: Option<usize>,
+}
+
+impl DatagramPerBFrame {
+    pub fn with_p_frame_threshold(mut self, threshold: usize) -> Self {
+        self.p_frame_threshold = Some(threshold);
+        self
+    }
+}
+
+impl VideoStreamer for DatagramPerBFrame {
+    fn new(mut namespace: Rendition) -> anyhow::Result<Self> {
+        let init_track = namespace
+            .create("init")
+            .ok_or_else(|| anyhow::anyhow!("Failed to create init track"))?
+            .stream(0)?
+            .append()?;
+        let mut video_track = namespace
+            .create("video")
+            .ok_or_else(|| anyhow::anyhow!("Failed to create video track"))?
+            .groups()?;
+        let current = video_track.append(i32::MAX.try_into().unwrap())?;
+
+        let b_frames_track = namespace
+            .create("b-frames")
+            .ok_or_else(|| anyhow::anyhow!("Failed to create b-frames track"))?
+            .datagrams()?;
+
+        Ok(DatagramPerBFrame {
+            init_track,
+            video_track,
+            current,
+
+            b_frames_track,
+            group_id: 0,
+            obj_id: 0,
+
+            p_frame_threshold: None,
+        })
+    }
+
+    fn stream(&mut self, buf: &mut BytesMut) -> Result<()> {
+        while next_item(buf)? {
+            let item = parse_item(buf)?;
+            match item {
+                MediaStreamItem::InitSegment(data) => {
+                    self.init_track.write(data)?;
+                }
+                MediaStreamItem::Frame(frame) => {
+                    let mut payload = BytesMut::new();
+                    serialize_frame(&frame, &mut payload)?;
+
+                    if frame.is_keyframe {
+                        self.group_id += 1;
+                        self.current = self.video_track.append(i32::MAX.try_into().unwrap())?;
+                    }
+
+                    let unreliable = match frame.frame_type {
+                        FrameType::B => true,
+                        FrameType::P => self
+                            .p_frame_threshold
+                            .is_some_and(|threshold| payload.len() <= threshold),
+                        FrameType::I => false,
+                    };
+
+                    if unreliable {
+                        // P-frames sent as datagrams are still more important than B-frames
+                        let priority = match frame.frame_type {
+                            FrameType::P => 1,
+                            _ => 0,
+                        };
+                        for fragment in fragment(self.obj_id, &payload)? {
+                            self.b_frames_track.write(Datagram {
+                                group_id: self.group_id,
+                                object_id: self.obj_id,
+                                priority,
+                                payload: fragment,
+                            })?;
+                        }
+                        self.obj_id += 1;
+                    } else {
+                        self.current.write(payload.freeze())?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Splits a frame into datagram payloads of at most MAX_DATAGRAM_PAYLOAD bytes.
+pub fn fragment(frame_id: u64, payload: &[u8]) -> Result<Vec<Bytes>> {
+    // an empty frame is still sent as a single fragment
+    let chunks: Vec<&[u8]> = match payload.is_empty() {
+        true => vec![payload],
+        false => payload
+            .chunks(MAX_DATAGRAM_PAYLOAD - FRAGMENT_HEADER)
+            .collect(),
+    };
+    let count = u16::try_from(chunks.len())
+        .map_err(|_| anyhow::anyhow!("Frame too large for datagrams"))?;
+
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let mut fragment = BytesMut::with_capacity(FRAGMENT_HEADER + chunk.len());
+            fragment.put_u64(frame_id);
+            fragment.put_u16(index as u16);
+            fragment.put_u16(count);
+            fragment.extend_from_slice(chunk);
+            fragment.freeze()
+        })
+        .collect())
+}
+
+// Reassembles the frames of a datagram track from their fragments.
+// Fragments may arrive in any order, frames missing a fragment are dropped
+// once there are too many incomplete frames.
+#[derive(Default)]
+pub struct Reassembler {
+    incomplete: BTreeMap<u64, Vec<Option<Bytes>>>,
+}
+
+impl Reassembler {
+    // returns the frame once all of its fragments were received
+    pub fn push(&mut self, mut fragment: Bytes) -> Result<Option<Bytes>> {
+        if fragment.len() < FRAGMENT_HEADER {
+            anyhow::bail!("fragment too short");
+        }
+        let frame_id = fragment.get_u64();
+        let index = fragment.get_u16() as usize;
+        let count = fragment.get_u16() as usize;
+        if index >= count {
+            anyhow::bail!("invalid fragment index");
+        }
+        if count == 1 {
+            return Ok(Some(fragment));
+        }
+
+        let fragments = self
+            .incomplete
+            .entry(frame_id)
+            .or_insert_with(|| vec![None; count]);
+        if fragments.len() != count {
+            anyhow::bail!("inconsistent fragment count");
+        }
+        fragments[index] = Some(fragment);
+
+        if fragments.iter().all(Option::is_some) {
+            let fragments = self.incomplete.remove(&frame_id).unwrap_or_default();
+            let frame = fragments.into_iter().flatten().collect::<Vec<_>>().concat();
+            return Ok(Some(frame.into()));
+        }
+
+        while self.incomplete.len() > MAX_INCOMPLETE_FRAMES {
+            self.incomplete.pop_first();
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use moq_transport::serve::{TrackReaderMode, Tracks};
+
+    use super::*;
+    use crate::broadcast::Broadcast;
+    use crate::video::parse_frame_header;
+
+    // a frame item of the media stream
+    fn item(frame_type: FrameType, decode_time: u64, size: usize) -> Vec<u8> {
+        let mut item = vec![0x01, (frame_type == FrameType::I) as u8, frame_type as u8];
+        item.extend_from_slice(&1_000_000_000u64.to_be_bytes());
+        item.extend_from_slice(&decode_time.to_be_bytes());
+        item.extend_from_slice(&decode_time.to_be_bytes());
+        item.extend_from_slice(&(size as u32).to_be_bytes());
+        item.extend(vec![decode_time as u8; size]);
+        item
+    }
+
+    #[test]
+    fn fragments_reassemble_in_any_order() {
+        let payload: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let mut fragments = fragment(7, &payload).unwrap();
+        assert_eq!(fragments.len(), 3);
+        assert!(fragments.iter().all(|f| f.len() <= MAX_DATAGRAM_PAYLOAD));
+
+        fragments.reverse();
+        let mut reassembler = Reassembler::default();
+        assert!(reassembler.push(fragments[0].clone()).unwrap().is_none());
+        assert!(reassembler.push(fragments[1].clone()).unwrap().is_none());
+        let frame = reassembler.push(fragments[2].clone()).unwrap().unwrap();
+        assert_eq!(&frame[..], &payload[..]);
+    }
+
+    #[test]
+    fn small_frame_is_a_single_fragment() {
+        let fragments = fragment(1, b"abc").unwrap();
+        assert_eq!(fragments.len(), 1);
+        let frame = Reassembler::default().push(fragments[0].clone()).unwrap();
+        assert_eq!(&frame.unwrap()[..], b"abc");
+    }
+
+    #[tokio::test]
+    async fn batch_of_b_frames_arrives() {
+        let (writer, _, mut reader) = Tracks::new("test".to_string()).produce();
+        let mut broadcast = Broadcast::new(writer);
+        let mut streamer = DatagramPerBFrame::new(broadcast.rendition(None, None)).unwrap();
+
+        let sizes = [100, 200, 5000, 300];
+        let mut input = item(FrameType::I, 0, 100);
+        for (i, size) in sizes.iter().enumerate() {
+            input.extend(item(FrameType::B, i as u64 + 1, *size));
+        }
+        // all frames are written in a row, before any of them is read
+        streamer.stream(&mut BytesMut::from(&input[..])).unwrap();
+        drop(streamer);
+
+        let track = reader.subscribe("b-frames").unwrap();
+        let TrackReaderMode::Datagrams(mut datagrams) = track.mode().await.unwrap() else {
+            panic!("expected a datagram track");
+        };
+        let mut reassembler = Reassembler::default();
+        let mut received = Vec::new();
+        while let Some(datagram) = datagrams.read().await.unwrap() {
+            assert!(datagram.payload.len() <= MAX_DATAGRAM_PAYLOAD);
+            if let Some(frame) = reassembler.push(datagram.payload).unwrap() {
+                received.push(parse_frame_header(&frame).unwrap().decode_time);
+            }
+        }
+        assert_eq!(received, vec![1, 2, 3, 4]);
+    }
+}
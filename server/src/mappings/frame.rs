@@ -0,0 +1,78 @@
+use crate::broadcast::Rendition;
+use crate::video::{
+    next_item, parse_item, serialize_frame, FrameType, MediaStreamItem, VideoStreamer,
+};
+use anyhow::Result;
+use bytes::BytesMut;
+use moq_transport::serve::{Object, ObjectsWriter, StreamGroupWriter};
+
+// Every frame is sent as its own object (and thus on its own stream).
+// Together with a frame deadline configured on the server, frames that can no longer
+// arrive in time are dropped instead of being sent (see deadline.rs). Only frames still
+// waiting on the server are dropped, a frame already handed to the session is sent in full.
+pub struct StreamPerFrame {
+    init_track: StreamGroupWriter,
+    video_track: ObjectsWriter,
+    group_id: u64,
+    obj_id: u64,
+}
+
+impl VideoStreamer for StreamPerFrame {
+    fn new(mut namespace: Rendition) -> anyhow::Result<Self> {
+        let init_track = namespace
+            .create("init")
+            .ok_or_else(|| anyhow::anyhow!("Failed to create init track"))?
+            .stream(0)?
+            .append()?;
+        let video_track = namespace
+            .create("video")
+            .ok_or_else(|| anyhow::anyhow!("Failed to create video track"))?
+            .objects()?;
+
+        Ok(StreamPerFrame {
+            init_track,
+            video_track,
+            group_id: 0,
+            obj_id: 0,
+        })
+    }
+
+    fn stream(&mut self, buf: &mut BytesMut) -> Result<()> {
+        while next_item(buf)? {
+            let item = parse_item(buf)?;
+            match item {
+                MediaStreamItem::InitSegment(data) => {
+                    self.init_track.write(data)?;
+                }
+                MediaStreamItem::Frame(frame) => {
+                    if frame.is_keyframe {
+                        self.group_id += 1;
+                    }
+
+                    // reference frames are sent in order and before any B-frame,
+                    // newer B-frames are prioritized over older ones since older ones
+                    // are more likely to miss their deadline anyway
+                    let priority: u64 = match frame.frame_type {
+                        FrameType::I | FrameType::P => i32::MAX.try_into().unwrap(),
+                        // the timestamp wraps around within 30 bits, which takes hours
+                        // at usual timescales, far more than the frames in flight span
+                        FrameType::B => frame.decode_time & ((1u64 << 30) - 1),
+                    };
+
+                    let mut payload = BytesMut::new();
+                    serialize_frame(&frame, &mut payload)?;
+                    self.video_track.write(
+                        Object {
+                            group_id: self.group_id,
+                            object_id: self.obj_id,
+                            priority,
+                        },
+                        payload.freeze(),
+                    )?;
+                    self.obj_id += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+}
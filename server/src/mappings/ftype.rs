@@ -1,10 +1,11 @@
+use crate::broadcast::Rendition;
 use crate::video::{
     next_item, parse_item, serialize_frame, serialize_frame_info, FrameInfo, FrameType,
     MediaStreamItem, VideoStreamer,
 };
 use anyhow::Result;
 use bytes::BytesMut;
-use moq_transport::serve::{Object, ObjectsWriter, StreamGroupWriter, StreamWriter, TracksWriter};
+use moq_transport::serve::{Object, ObjectsWriter, StreamGroupWriter, StreamWriter};
 
 pub struct StreamPerFrameType {
     init_track: StreamGroupWriter,
@@ -16,7 +17,7 @@ pub struct StreamPerFrameType {
 }
 
 impl VideoStreamer for StreamPerFrameType {
-    fn new(mut namespace: TracksWriter) -> anyhow::Result<Self> {
+    fn new(mut namespace: Rendition) -> anyhow::Result<Self> {
         let init_track = namespace
             .create("init")
             .ok_or_else(|| anyhow::anyhow!("Failed to create init track"))?
@@ -50,36 +51,33 @@ impl VideoStreamer for StreamPerFrameType {
                     self.init_track.write(data)?;
                 }
                 MediaStreamItem::Frame(frame) => {
+                    if frame.is_keyframe {
+                        self.group_id += 1;
+                        self.current = self.frames_track.append()?;
+                    }
+
                     let priority = match frame.frame_type {
                         FrameType::I => 2,
                         FrameType::P => 2,
                         FrameType::B => {
-                            let timestamp = frame.decode_time;
+                            // the timestamp wraps around within 30 bits, which takes hours
+                            // at usual timescales, far more than the frames in flight span
                             let max_value = (1u64 << 30) - 1;
-                            if timestamp > max_value {
-                                return Err(anyhow::anyhow!(
-                                    "Timestamp overflow in priority calculation"
-                                ));
-                            }
+                            let timestamp = frame.decode_time & max_value;
                             (2 << 30) + (max_value - timestamp)
                         }
                     };
 
-                    if frame.is_keyframe {
-                        self.group_id += 1;
-                        self.current = self.frames_track.append()?;
-                    }
-
                     // write frame info to frames track
-                    let mut infoPayload = BytesMut::new();
+                    let mut info_payload = BytesMut::new();
                     serialize_frame_info(
                         FrameInfo {
                             ftype: frame.frame_type,
                             dts: frame.decode_time,
                         },
-                        &mut infoPayload,
+                        &mut info_payload,
                     )?;
-                    self.current.write(infoPayload.freeze())?;
+                    self.current.write(info_payload.freeze())?;
 
                     // write frame to video track
                     let mut payload = BytesMut::new();
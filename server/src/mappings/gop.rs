@@ -1,7 +1,8 @@
+use crate::broadcast::Rendition;
 use crate::video::{next_item, parse_item, serialize_frame, MediaStreamItem, VideoStreamer};
 use anyhow::Result;
 use bytes::BytesMut;
-use moq_transport::serve::{GroupWriter, GroupsWriter, StreamGroupWriter, TracksWriter};
+use moq_transport::serve::{GroupWriter, GroupsWriter, StreamGroupWriter};
 
 pub struct StreamPerGop {
     init_track: StreamGroupWriter,
@@ -11,7 +12,7 @@ pub struct StreamPerGop {
 }
 
 impl VideoStreamer for StreamPerGop {
-    fn new(mut namespace: TracksWriter) -> anyhow::Result<Self> {
+    fn new(mut namespace: Rendition) -> anyhow::Result<Self> {
         let init_track = namespace
             .create("init")
             .ok_or_else(|| anyhow::anyhow!("Failed to create init track"))?
@@ -0,0 +1,66 @@
+use crate::broadcast::Rendition;
+use crate::video::{
+    next_item, parse_item, serialize_frame, FrameType, MediaStreamItem, VideoStreamer,
+};
+use anyhow::Result;
+use bytes::BytesMut;
+use moq_transport::serve::{GroupWriter, GroupsWriter, StreamGroupWriter};
+
+// One group per mini-GOP, i.e. an anchor frame (I or P) followed by the B-frames
+// that depend on it in decode order, so congestion drops whole mini-GOPs.
+// Newer mini-GOPs are prioritized over older ones.
+pub struct StreamPerMiniGop {
+    init_track: StreamGroupWriter,
+    video_track: GroupsWriter,
+    current_group: Option<GroupWriter>,
+    mini_gop: u32,
+}
+
+impl VideoStreamer for StreamPerMiniGop {
+    fn new(mut namespace: Rendition) -> anyhow::Result<Self> {
+        let init_track = namespace
+            .create("init")
+            .ok_or_else(|| anyhow::anyhow!("Failed to create init track"))?
+            .stream(0)?
+            .append()?;
+        let video_track = namespace
+            .create("video")
+            .ok_or_else(|| anyhow::anyhow!("Failed to create video track"))?
+            .groups()?;
+        Ok(StreamPerMiniGop {
+            init_track,
+            video_track,
+            current_group: None,
+            mini_gop: 0,
+        })
+    }
+
+    fn stream(&mut self, buf: &mut BytesMut) -> Result<()> {
+        while next_item(buf)? {
+            let item = parse_item(buf)?;
+            match item {
+                MediaStreamItem::InitSegment(data) => {
+                    self.init_track.write(data)?;
+                }
+                MediaStreamItem::Frame(frame) => {
+                    if frame.frame_type != FrameType::B {
+                        // saturates after 2^31 mini-GOPs, years at any sensible frame rate
+                        let priority = self.mini_gop.min(i32::MAX as u32);
+                        self.current_group = Some(self.video_track.append(priority.into())?);
+                        self.mini_gop = self.mini_gop.saturating_add(1);
+                    }
+
+                    let current_group = self
+                        .current_group
+                        .as_mut()
+                        .ok_or_else(|| anyhow::anyhow!("No current group"))?;
+
+                    let mut payload = BytesMut::new();
+                    serialize_frame(&frame, &mut payload)?;
+                    current_group.write(payload.freeze())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,30 @@
 mod bframe;
+mod datagram;
+mod frame;
 mod ftype;
 mod gop;
+mod minigop;
+mod temporal;
 mod track;
 
 pub use bframe::*;
+pub use datagram::*;
+pub use frame::*;
 pub use ftype::*;
 pub use gop::*;
+pub use minigop::*;
+pub use temporal::*;
 pub use track::*;
+
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mapping {
+    Track,
+    Gop,
+    FrameType,
+    BFrame,
+    Frame,
+    Datagram,
+    Temporal,
+    MiniGop,
+}
@@ -0,0 +1,107 @@
+use crate::broadcast::Rendition;
+use crate::video::{
+    next_item, parse_item, serialize_frame, FrameType, MediaStreamItem, VideoStreamer,
+};
+use anyhow::Result;
+use bytes::BytesMut;
+use moq_transport::serve::{GroupWriter, GroupsWriter, StreamGroupWriter};
+
+const TEMPORAL_LAYERS: usize = 3;
+
+// One track per temporal layer (video/t0, video/t1, ...), each with a group per GOP.
+// Lower layers don't depend on higher ones, so subscribing to a subset of the tracks
+// yields a lower frame rate. Priorities strictly decrease with the layer.
+//
+// The ingest doesn't carry temporal ids, so they are derived from the frame types
+// assuming a hierarchical B-pyramid (the default for x264):
+// - t0: I- and P-frames
+// - t1: the first B-frame after an anchor frame in decode order, which is the one
+//   referenced by the other B-frames of the mini-GOP
+// - t2: the remaining (non-reference) B-frames
+pub struct TrackPerTemporalLayer {
+    init_track: StreamGroupWriter,
+    layers: Vec<Layer>,
+    b_frame_index: u32,
+}
+
+struct Layer {
+    track: GroupsWriter,
+    current: Option<GroupWriter>,
+    priority: u64,
+}
+
+impl TrackPerTemporalLayer {
+    fn temporal_layer(&self, frame_type: FrameType) -> usize {
+        match frame_type {
+            FrameType::I | FrameType::P => 0,
+            FrameType::B if self.b_frame_index == 0 => 1,
+            FrameType::B => 2,
+        }
+    }
+}
+
+impl VideoStreamer for TrackPerTemporalLayer {
+    fn new(mut namespace: Rendition) -> anyhow::Result<Self> {
+        let init_track = namespace
+            .create("init")
+            .ok_or_else(|| anyhow::anyhow!("Failed to create init track"))?
+            .stream(0)?
+            .append()?;
+
+        let mut layers = Vec::with_capacity(TEMPORAL_LAYERS);
+        for layer in 0..TEMPORAL_LAYERS {
+            let track = namespace
+                .create(&format!("video/t{}", layer))
+                .ok_or_else(|| anyhow::anyhow!("Failed to create video track"))?
+                .groups()?;
+            let priority: u64 = i32::MAX.try_into().unwrap();
+            layers.push(Layer {
+                track,
+                current: None,
+                priority: priority - layer as u64,
+            });
+        }
+
+        Ok(TrackPerTemporalLayer {
+            init_track,
+            layers,
+            b_frame_index: 0,
+        })
+    }
+
+    fn stream(&mut self, buf: &mut BytesMut) -> Result<()> {
+        while next_item(buf)? {
+            let item = parse_item(buf)?;
+            match item {
+                MediaStreamItem::InitSegment(data) => {
+                    self.init_track.write(data)?;
+                }
+                MediaStreamItem::Frame(frame) => {
+                    // start a new group on every layer, so groups line up across layers
+                    if frame.is_keyframe {
+                        for layer in self.layers.iter_mut() {
+                            layer.current = Some(layer.track.append(layer.priority)?);
+                        }
+                    }
+
+                    let index = self.temporal_layer(frame.frame_type);
+                    if frame.frame_type == FrameType::B {
+                        self.b_frame_index += 1;
+                    } else {
+                        self.b_frame_index = 0;
+                    }
+
+                    let current = self.layers[index]
+                        .current
+                        .as_mut()
+                        .ok_or_else(|| anyhow::anyhow!("No current group"))?;
+
+                    let mut payload = BytesMut::new();
+                    serialize_frame(&frame, &mut payload)?;
+                    current.write(payload.freeze())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,8 @@
+use crate::broadcast::Rendition;
 use crate::video::{next_item, parse_item, serialize_frame, MediaStreamItem, VideoStreamer};
 use anyhow::Result;
 use bytes::BytesMut;
-use moq_transport::serve::{StreamGroupWriter, StreamWriter, TracksWriter};
+use moq_transport::serve::{StreamGroupWriter, StreamWriter};
 
 pub struct StreamPerTrack {
     init_track: StreamGroupWriter,
@@ -10,7 +11,7 @@ pub struct StreamPerTrack {
 }
 
 impl VideoStreamer for StreamPerTrack {
-    fn new(mut namespace: TracksWriter) -> anyhow::Result<Self> {
+    fn new(mut namespace: Rendition) -> anyhow::Result<Self> {
         let init_track = namespace
             .create("init")
             .ok_or_else(|| anyhow::anyhow!("Failed to create init track"))?
@@ -0,0 +1,310 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use moq_transport::serve::{TrackReader, TrackReaderMode};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::{cache::CachedObject, video::parse_frame_header};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+// Writes the events of a connection as a qlog trace (JSON-SEQ, draft 0.3) to
+// <dir>/<connection id>.sqlog, where the connection id is the id used in the logs (conn=).
+//
+// quinn doesn't expose its packet-level events, so the QUIC events are derived from the
+// connection statistics, sampled periodically (recovery:metrics_updated, recovery:packets_lost).
+// The MoQ events are logged by the server: subscriptions, the priorities assigned to the
+// streams of all tracks sent, the groups opened for the subscribers' copies of the tracks and
+// the objects sent or dropped, with both their ids on the wire and in the original track.
+// A stream reset is logged when it ends the subscription, i.e. on stream tracks. The streams of
+// group and object tracks are served by the transport on their own, their resets aren't visible.
+//
+// The events are written to the file by a dedicated thread. A disabled trace ignores all events.
+#[derive(Clone, Default)]
+pub struct Qlog {
+    trace: Option<Arc<Trace>>,
+}
+
+struct Trace {
+    started: Instant,
+    records: mpsc::UnboundedSender<Value>,
+}
+
+impl Qlog {
+    pub fn new(dir: Option<&Path>, connection: &quinn::Connection) -> anyhow::Result<Self> {
+        let Some(dir) = dir else {
+            return Ok(Self::default());
+        };
+
+        let id = connection.stable_id();
+        let path = dir.join(format!("{}.sqlog", id));
+        let file =
+            File::create(&path).with_context(|| format!("failed to create {}", path.display()))?;
+
+        let reference_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+            * 1000.0;
+        let (records, receiver) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            if let Err(err) = write(BufWriter::new(file), receiver) {
+                log::warn!("failed to write qlog trace {}: {}", path.display(), err);
+            }
+        });
+
+        let trace = Trace {
+            started: Instant::now(),
+            records,
+        };
+        trace.write(json!({
+            "qlog_version": "0.3",
+            "qlog_format": "JSON-SEQ",
+            "title": format!("connection {}", id),
+            "trace": {
+                "vantage_point": { "type": "server" },
+                "common_fields": {
+                    "group_id": id.to_string(),
+                    "time_format": "relative",
+                    "reference_time": reference_time,
+                },
+            },
+        }));
+
+        let qlog = Self {
+            trace: Some(Arc::new(trace)),
+        };
+        qlog.event(
+            "transport:connection_started",
+            json!({
+                "src_ip": connection.local_ip().map(|ip| ip.to_string()),
+                "dst_ip": connection.remote_address().ip().to_string(),
+                "dst_port": connection.remote_address().port(),
+            }),
+        );
+        Ok(qlog)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    pub fn event(&self, name: &str, data: Value) {
+        let Some(trace) = &self.trace else {
+            return;
+        };
+        let time = trace.started.elapsed().as_secs_f64() * 1000.0;
+        trace.write(json!({ "time": time, "name": name, "data": data }));
+    }
+
+    pub fn subscribe(&self, namespace: &str, track: &str) {
+        self.event(
+            "moq:subscribe",
+            json!({ "namespace": namespace, "track": track }),
+        );
+    }
+
+    pub fn subscribe_done(&self, namespace: &str, track: &str, error: Option<String>) {
+        self.event(
+            "moq:subscribe_done",
+            json!({ "namespace": namespace, "track": track, "error": error }),
+        );
+    }
+
+    // the subscription ended with an error, resetting the streams of the track
+    pub fn stream_reset(&self, namespace: &str, track: &str, code: u64, reason: String) {
+        self.event(
+            "moq:stream_reset",
+            json!({ "namespace": namespace, "track": track, "code": code, "reason": reason }),
+        );
+    }
+
+    // Logs the priorities of the streams a track is sent on, until the track ends: one
+    // stream for the whole track, one per group or one per object, depending on its mode.
+    pub fn priorities(&self, track: TrackReader) {
+        if !self.enabled() {
+            return;
+        }
+        let qlog = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = qlog.watch_priorities(track).await {
+                log::debug!("stopped logging priorities: {}", err);
+            }
+        });
+    }
+
+    async fn watch_priorities(&self, track: TrackReader) -> anyhow::Result<()> {
+        let name = track.name.as_str();
+        match track.mode().await? {
+            TrackReaderMode::Stream(stream) => {
+                self.priority_assigned(name, "track", None, None, Some(stream.priority))
+            }
+            TrackReaderMode::Groups(mut groups) => {
+                while let Some(group) = groups.next().await? {
+                    let group_id = Some(group.group_id);
+                    self.priority_assigned(name, "group", group_id, None, Some(group.priority));
+                }
+            }
+            TrackReaderMode::Objects(mut objects) => {
+                while let Some(object) = objects.next().await? {
+                    let (group_id, object_id) = (Some(object.group_id), Some(object.object_id));
+                    self.priority_assigned(
+                        name,
+                        "object",
+                        group_id,
+                        object_id,
+                        Some(object.priority),
+                    );
+                }
+            }
+            // datagrams aren't prioritized by the transport
+            TrackReaderMode::Datagrams(_) => {
+                self.priority_assigned(name, "datagram", None, None, None)
+            }
+        }
+        Ok(())
+    }
+
+    fn priority_assigned(
+        &self,
+        track: &str,
+        stream: &str,
+        group_id: Option<u64>,
+        object_id: Option<u64>,
+        priority: Option<u64>,
+    ) {
+        self.event(
+            "moq:priority_assigned",
+            json!({
+                "track": track,
+                "stream": stream,
+                "group_id": group_id,
+                "object_id": object_id,
+                "priority": priority,
+            }),
+        );
+    }
+
+    // a group of a subscriber's copy of a track, with its id on the wire
+    pub fn group_opened(&self, track: &str, object: &CachedObject, group_id: u64) {
+        self.event(
+            "moq:group_opened",
+            json!({
+                "track": track,
+                "group_id": group_id,
+                "source_group_id": object.group_id,
+                "priority": object.priority,
+            }),
+        );
+    }
+
+    // an object of a subscriber's copy of a track, with its group and object id on the wire
+    pub fn object_sent(&self, track: &str, object: &CachedObject, wire: (u64, u64), length: usize) {
+        if !self.enabled() {
+            return;
+        }
+        let frame_type = parse_frame_header(&object.payload)
+            .ok()
+            .map(|header| format!("{:?}", header.frame_type));
+        self.event(
+            "moq:object_sent",
+            json!({
+                "track": track,
+                "group_id": wire.0,
+                "object_id": wire.1,
+                "source_group_id": object.group_id,
+                "source_object_id": object.object_id,
+                "priority": object.priority,
+                "length": length,
+                "frame_type": frame_type,
+            }),
+        );
+    }
+
+    pub fn object_dropped(&self, track: &str, object: &CachedObject, reason: &str) {
+        self.event(
+            "moq:object_dropped",
+            json!({
+                "track": track,
+                "source_group_id": object.group_id,
+                "source_object_id": object.object_id,
+                "reason": reason,
+            }),
+        );
+    }
+
+    // samples the connection's statistics until it is closed
+    pub async fn run(self, connection: quinn::Connection) {
+        if !self.enabled() {
+            return;
+        }
+
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        let mut previous = connection.stats();
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                err = connection.closed() => {
+                    self.event("transport:connection_closed", json!({ "reason": err.to_string() }));
+                    break;
+                }
+            }
+
+            let current = connection.stats();
+            if current.path.rtt != previous.path.rtt || current.path.cwnd != previous.path.cwnd {
+                self.event(
+                    "recovery:metrics_updated",
+                    json!({
+                        "smoothed_rtt": current.path.rtt.as_secs_f64() * 1000.0,
+                        "congestion_window": current.path.cwnd,
+                        "packets_sent": current.path.sent_packets,
+                        "bytes_sent": current.udp_tx.bytes,
+                    }),
+                );
+            }
+            let lost = current.path.lost_packets - previous.path.lost_packets;
+            if lost > 0 {
+                self.event(
+                    "recovery:packets_lost",
+                    json!({
+                        "count": lost,
+                        "bytes": current.path.lost_bytes - previous.path.lost_bytes,
+                    }),
+                );
+            }
+            previous = current;
+        }
+    }
+}
+
+impl Trace {
+    fn write(&self, record: Value) {
+        // the writer only stops on errors, which it logs
+        self.records.send(record).ok();
+    }
+}
+
+// Writes the records until all handles of the trace are dropped, flushing whenever
+// there are no more pending. Each record is preceded by the record separator.
+fn write(
+    mut writer: BufWriter<File>,
+    mut records: mpsc::UnboundedReceiver<Value>,
+) -> anyhow::Result<()> {
+    while let Some(record) = records.blocking_recv() {
+        writer.write_all(b"\x1e")?;
+        serde_json::to_writer(&mut writer, &record)?;
+        writer.write_all(b"\n")?;
+        if records.is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
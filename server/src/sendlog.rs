@@ -0,0 +1,218 @@
+use std::{
+    fmt::Write as _,
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::{cache::CachedObject, video::parse_frame_header};
+
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SendLogFormat {
+    #[default]
+    Csv,
+    Jsonl,
+}
+
+impl SendLogFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            SendLogFormat::Csv => "csv",
+            SendLogFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+// What happened to a frame of a subscriber's track.
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Sent,
+    // dropped by the deadline filter
+    Deadline,
+    // dropped by the frame dropper
+    Congestion,
+    // delayed too long by the egress limits
+    Egress,
+    // the subscriber's copy of the track was closed while writing it, e.g. after a stream reset
+    Reset,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Sent => "sent",
+            Outcome::Deadline => "deadline",
+            Outcome::Congestion => "congestion",
+            Outcome::Egress => "egress",
+            Outcome::Reset => "reset",
+        }
+    }
+}
+
+// A record per frame, times are in milliseconds since the unix epoch.
+#[derive(Serialize)]
+struct Record<'a> {
+    track: &'a str,
+    group_id: u64,
+    object_id: u64,
+    frame_type: Option<String>,
+    priority: u64,
+    size: usize,
+    // when the frame was ingested, i.e. its availability time
+    ingested_ms: Option<f64>,
+    // when the frame was cached after the mapping wrote it
+    cached_ms: f64,
+    // when the frame was handed to the subscriber's copy of the track
+    enqueued_ms: Option<f64>,
+    // when the group (or object, for tracks sent as objects) carrying the frame was started
+    group_started_ms: Option<f64>,
+    // the session's estimated queue delay at the time
+    queue_delay_ms: f64,
+    outcome: Outcome,
+}
+
+const CSV_HEADER: &str = "track,group_id,object_id,frame_type,priority,size,ingested_ms,cached_ms,enqueued_ms,group_started_ms,queue_delay_ms,outcome";
+
+// Logs the frames of a subscriber's track to <dir>/<connection id>-<namespace>-<track>.<csv|jsonl>,
+// so the server-side queueing delay can be computed per frame type.
+// The streams are opened by the transport once the subscription picks up the group, so the
+// time the group was started is logged instead. A stream reset is only noticed when writing a
+// frame to the subscriber's copy of the track fails.
+//
+// The file is opened and written by a dedicated thread, so subscribing doesn't wait for it.
+pub struct SendLog {
+    lines: mpsc::UnboundedSender<String>,
+    format: SendLogFormat,
+    track: String,
+}
+
+impl SendLog {
+    pub fn create(
+        dir: &Path,
+        format: SendLogFormat,
+        connection: usize,
+        namespace: &str,
+        track: &str,
+    ) -> Self {
+        let name = format!("{}-{}-{}", connection, namespace, track).replace('/', "_");
+        let path = dir.join(format!("{}.{}", name, format.extension()));
+
+        let (lines, receiver) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            if let Err(err) = write_lines(&path, format, receiver) {
+                log::warn!("failed to write send log: {:#}", err);
+            }
+        });
+
+        Self {
+            lines,
+            format,
+            track: track.to_string(),
+        }
+    }
+
+    pub fn log(
+        &mut self,
+        object: &CachedObject,
+        outcome: Outcome,
+        enqueued: Option<SystemTime>,
+        group_started: Option<SystemTime>,
+        queue_delay: Duration,
+    ) {
+        let header = parse_frame_header(&object.payload).ok();
+        let record = Record {
+            track: &self.track,
+            group_id: object.group_id,
+            object_id: object.object_id,
+            frame_type: header
+                .as_ref()
+                .map(|header| format!("{:?}", header.frame_type)),
+            priority: object.priority,
+            size: object.payload.len(),
+            ingested_ms: header.map(|header| millis(header.availability_time)),
+            cached_ms: millis(object.cached),
+            enqueued_ms: enqueued.map(millis),
+            group_started_ms: group_started.map(millis),
+            queue_delay_ms: queue_delay.as_secs_f64() * 1000.0,
+            outcome,
+        };
+        match format_record(self.format, &record) {
+            // the writer only stops on errors, which it logs
+            Ok(line) => drop(self.lines.send(line)),
+            Err(err) => log::warn!("failed to format send log record: {}", err),
+        }
+    }
+}
+
+// Writes the lines until the log is dropped. A track subscribed again by the same session
+// is appended to the same file.
+fn write_lines(
+    path: &Path,
+    format: SendLogFormat,
+    mut lines: mpsc::UnboundedReceiver<String>,
+) -> anyhow::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let empty = file.metadata()?.len() == 0;
+
+    let mut writer = BufWriter::new(file);
+    if empty && format == SendLogFormat::Csv {
+        writeln!(writer, "{}", CSV_HEADER)?;
+    }
+    while let Some(line) = lines.blocking_recv() {
+        writer.write_all(line.as_bytes())?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn format_record(format: SendLogFormat, record: &Record) -> anyhow::Result<String> {
+    let mut line = String::new();
+    match format {
+        SendLogFormat::Jsonl => {
+            line = serde_json::to_string(record)?;
+            line.push('\n');
+        }
+        SendLogFormat::Csv => {
+            writeln!(
+                line,
+                "{},{},{},{},{},{},{},{:.3},{},{},{:.3},{}",
+                record.track,
+                record.group_id,
+                record.object_id,
+                record.frame_type.as_deref().unwrap_or_default(),
+                record.priority,
+                record.size,
+                optional(record.ingested_ms),
+                record.cached_ms,
+                optional(record.enqueued_ms),
+                optional(record.group_started_ms),
+                record.queue_delay_ms,
+                record.outcome.as_str(),
+            )?;
+        }
+    }
+    Ok(line)
+}
+
+pub fn millis(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+        * 1000.0
+}
+
+// an empty CSV field if missing
+fn optional(ms: Option<f64>) -> String {
+    ms.map(|ms| format!("{:.3}", ms)).unwrap_or_default()
+}
@@ -1,88 +1,286 @@
-use std::{future::Future, net, pin::Pin};
+use std::{future::Future, net, sync::Arc, time::Duration};
 
 use anyhow::Context;
-use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
-use moq_native::quic;
+use futures::{future::BoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
 use moq_transport::{
-    serve::{ServeError, TracksReader},
+    serve::{ServeError, Track, TrackReader, TrackReaderMode},
     session::{Publisher, Session, SessionError, Subscribed},
 };
 
-use crate::local::Locals;
+use crate::{
+    abr::Auto,
+    broadcast::{read_catalog, Catalog},
+    cache::{CacheMode, JoinMode},
+    config::{AuthConfig, EgressConfig, ImpairConfig, Limits, LoggingConfig},
+    congestion::FrameDropper,
+    deadline::Deadline,
+    egress::{Egress, GlobalEgress},
+    forward::{Forwarder, PriorityPolicy, Sink},
+    impair::{self, Bandwidth, BandwidthLog, Relay},
+    limits::{Admission, IDLE_CODE, PROTOCOL_VIOLATION_CODE, TOO_MANY_SUBSCRIPTIONS_CODE},
+    listener::{Accepted, Listener},
+    local::Locals,
+    qlog::Qlog,
+    sendlog::SendLog,
+    sessions::Sessions,
+    shutdown::Drain,
+    stats::SessionStats,
+};
 
 pub struct ServerConfig {
-    /// Listen on this address
-    pub bind: net::SocketAddr,
+    /// Listen on these addresses
+    pub listeners: Vec<net::SocketAddr>,
 
     /// The TLS configuration.
     pub tls: moq_native::tls::Config,
+
+    /// The addresses clients may connect from.
+    pub auth: AuthConfig,
+
+    pub limits: Limits,
+
+    /// Egress rate limits per session and for all sessions.
+    pub egress: EgressConfig,
+
+    /// Network impairments emulated on the listeners.
+    pub impairment: ImpairConfig,
+
+    /// Traces written for offline analysis.
+    pub logging: LoggingConfig,
+
+    /// Notifies the server when the process is shutting down.
+    pub drain: Drain,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct ForwardConfig {
+    /// Drop frames that can no longer arrive before their deadline.
+    pub deadline: Option<Deadline>,
+
+    /// Drop frames when the session's queue delay exceeds this budget.
+    pub drop_budget: Option<Duration>,
+
+    /// Where new subscribers start.
+    pub join: JoinMode,
+
+    /// How the priorities of the objects are set.
+    pub priority: PriorityPolicy,
 }
 
 pub struct Server {
-    quic: quic::Endpoint,
+    listeners: Vec<Listener>,
+    relays: Vec<Relay>,
+    bandwidth_log: Option<BandwidthLog>,
     locals: Locals,
-    // just so it is not dropped
-    _namespace: TracksReader,
+    sessions: Sessions,
+    limits: Limits,
+    egress: EgressConfig,
+    global_egress: Option<GlobalEgress>,
+    logging: LoggingConfig,
+    drain: Drain,
 }
 
 impl Server {
-    pub async fn new(config: ServerConfig, namespace: TracksReader) -> anyhow::Result<Self> {
-        let quic = quic::Endpoint::new(quic::Config {
-            bind: config.bind,
-            tls: config.tls,
-        })?;
+    pub async fn new(
+        config: ServerConfig,
+        locals: Locals,
+        sessions: Sessions,
+    ) -> anyhow::Result<Self> {
+        config.logging.create_dirs()?;
 
-        let mut locals = Locals::new();
-        locals.register(namespace.clone()).await?;
+        // the bandwidth trace is replayed on all listeners at once
+        let impaired = config.impairment.enabled();
+        // behind the relay, all clients connect from localhost
+        if impaired
+            && (!config.auth.allow.is_empty() || config.limits.max_connections_per_ip.is_some())
+        {
+            anyhow::bail!("network impairment can't be combined with auth or per-IP limits");
+        }
+        let bandwidth = Bandwidth::new(&config.impairment)?;
+        let bandwidth_log = match &config.impairment.bandwidth_log {
+            Some(_) if !impaired => {
+                anyhow::bail!("bandwidth log requires network impairment");
+            }
+            Some(path) => Some(BandwidthLog::create(path, bandwidth.clone())?),
+            None => None,
+        };
+
+        // the session limits are shared by the listeners
+        let admission = Arc::new(Admission::new(config.auth, &config.limits));
+        let mut listeners = Vec::new();
+        let mut relays = Vec::new();
+        for bind in config.listeners {
+            // the QUIC endpoint listens on localhost behind the relay
+            let listener = Listener::bind(
+                match impaired {
+                    true => impair::endpoint_bind(bind),
+                    false => bind,
+                },
+                &config.tls,
+                admission.clone(),
+                &config.limits,
+            )?;
+            if impaired {
+                let endpoint = listener.local_addr()?;
+                let relay =
+                    Relay::bind(bind, endpoint, config.impairment.clone(), bandwidth.clone());
+                relays.push(relay.await?);
+            }
+            listeners.push(listener);
+        }
 
         Ok(Self {
-            quic,
+            listeners,
+            relays,
+            bandwidth_log,
             locals,
-            _namespace: namespace,
+            sessions,
+            limits: config.limits,
+            egress: config.egress,
+            global_egress: GlobalEgress::new(&config.egress),
+            logging: config.logging,
+            drain: config.drain,
         })
     }
 
-    pub async fn run(self) -> anyhow::Result<()> {
-        let mut tasks: FuturesUnordered<
-            Pin<Box<dyn Future<Output = Result<(), anyhow::Error>> + Send>>,
-        > = FuturesUnordered::new();
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        let listeners = std::mem::take(&mut self.listeners);
+        let relays = std::mem::take(&mut self.relays);
+        let bandwidth_log = self.bandwidth_log.take().map(|writer| {
+            tokio::spawn(async move {
+                if let Err(err) = writer.run().await {
+                    log::warn!("failed to write bandwidth log: {:#}", err);
+                }
+            })
+        });
+        let listeners = futures::future::try_join_all(
+            listeners.into_iter().map(|listener| self.listen(listener)),
+        );
+        // the relays run until the listeners are done
+        let impaired = !relays.is_empty();
+        let relays = futures::future::try_join_all(relays.into_iter().map(Relay::run));
+        tokio::select! {
+            res = listeners => res?,
+            res = relays, if impaired => res?,
+        };
+        if let Some(task) = bandwidth_log {
+            task.abort();
+        }
+        Ok(())
+    }
+
+    async fn listen(&self, mut listener: Listener) -> anyhow::Result<()> {
+        let mut tasks: FuturesUnordered<BoxFuture<'static, anyhow::Result<()>>> =
+            FuturesUnordered::new();
 
-        let mut server = self.quic.server.context("missing TLS certificate")?;
-        log::info!("listening on {}", server.local_addr()?);
+        let mut drain = self.drain.clone();
+        log::info!("listening on {}", listener.local_addr()?);
 
         loop {
             tokio::select! {
-                res = server.accept() => {
-                    let conn = res.context("failed to accept QUIC connection")?;
+                res = listener.accept() => {
+                    let Accepted { connection, session, permit } =
+                        res.context("failed to accept QUIC connection")?;
+                    let remote = connection.remote_address();
+
+                    let qlog = match Qlog::new(self.logging.qlog_dir.as_deref(), &connection) {
+                        Ok(qlog) => qlog,
+                        Err(err) => {
+                            log::warn!("failed to create qlog trace: {:#}", err);
+                            Qlog::default()
+                        }
+                    };
+                    tokio::spawn(qlog.clone().run(connection.clone()));
 
                     let locals = self.locals.clone();
+                    let sessions = self.sessions.clone();
+                    let logging = self.logging.clone();
+                    let limits = self.limits;
+                    let egress = Egress::new(&self.egress, self.global_egress.clone());
+                    let drain = self.drain.clone();
 
                     tasks.push(async move {
-                        let (session, publisher, _) = match Session::accept(conn).await {
-                            Ok(session) => session,
-                            Err(err) => {
+                        // released once the session ended
+                        let _permit = permit;
+                        let stats = SessionStats::new(connection.clone());
+                        let timeout = Duration::from_millis(limits.handshake_timeout);
+                        let setup = tokio::time::timeout(timeout, Session::accept(session));
+                        let (session, publisher, _) = match setup.await {
+                            Ok(Ok(session)) => session,
+                            Ok(Err(err)) => {
                                 log::warn!("failed to accept MoQ session: {}", err);
                                 return Ok(());
                             }
+                            Err(_) => {
+                                log::warn!("MoQ setup from {} timed out", remote);
+                                let code = quinn::VarInt::from_u32(PROTOCOL_VIOLATION_CODE);
+                                connection.close(code, b"setup timeout");
+                                return Ok(());
+                            }
                         };
-                        log::info!("established MoQ session");
+                        log::info!("established MoQ session with {}", remote);
+                        let _registration = sessions.register(stats.clone(), connection.clone());
 
-                        let producer = Producer::new(publisher.unwrap(), locals);
+                        let producer = Producer::new(
+                            publisher.unwrap(),
+                            locals,
+                            sessions,
+                            stats.clone(),
+                            qlog,
+                            SessionConfig {
+                                limits,
+                                logging,
+                                egress,
+                            },
+                            drain.clone(),
+                        );
                         let mut tasks = FuturesUnordered::new();
                         tasks.push(session.run().boxed());
                         tasks.push(producer.run().boxed());
+                        tasks.push(async move {
+                            stats.run().await;
+                            Ok(())
+                        }.boxed());
 
                         log::info!("running MoQ session");
-                        if let Err(err) = tasks.select_next_some().await {
-                            log::warn!("failed to run MoQ session: {}", err);
+                        let mut grace = drain.clone();
+                        tokio::select! {
+                            res = tasks.select_next_some() => if let Err(err) = res {
+                                log::warn!("failed to run MoQ session: {}", err);
+                            },
+                            _ = grace.expired() => log::info!("drain grace period expired"),
+                        }
+
+                        // the producer returns once all subscriptions are done,
+                        // otherwise the session is closed after the grace period
+                        if drain.is_draining() {
+                            log::info!("closing drained MoQ session");
+                            drain.close(&connection);
                         }
 
                         Ok(())
                     }.boxed());
                 },
-                res = tasks.next(), if !tasks.is_empty() => res.unwrap()?,
+                res = tasks.next(), if !tasks.is_empty() => {
+                    if let Some(Err(err)) = res {
+                        log::warn!("failed to run session: {:#}", err);
+                    }
+                },
+                _ = drain.wait() => break,
             }
         }
+
+        // stop accepting sessions and wait for the current ones to be drained
+        drop(listener);
+        log::info!("draining {} sessions", tasks.len());
+        // a failed session doesn't stop the others from draining
+        while let Some(res) = tasks.next().await {
+            if let Err(err) = res {
+                log::warn!("failed to drain session: {:#}", err);
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -90,44 +288,270 @@ impl Server {
 pub struct Producer {
     publisher: Publisher,
     locals: Locals,
+    sessions: Sessions,
+    stats: SessionStats,
+    qlog: Qlog,
+    config: SessionConfig,
+    drain: Drain,
+}
+
+// How the subscriptions of a session are served.
+#[derive(Clone)]
+pub struct SessionConfig {
+    pub limits: Limits,
+    pub logging: LoggingConfig,
+    // the session's egress rate limits, if limited
+    pub egress: Option<Egress>,
 }
 
 impl Producer {
-    pub fn new(publisher: Publisher, locals: Locals) -> Self {
-        Self { publisher, locals }
+    pub fn new(
+        publisher: Publisher,
+        locals: Locals,
+        sessions: Sessions,
+        stats: SessionStats,
+        qlog: Qlog,
+        config: SessionConfig,
+        drain: Drain,
+    ) -> Self {
+        Self {
+            publisher,
+            locals,
+            sessions,
+            stats,
+            qlog,
+            config,
+            drain,
+        }
     }
 
     pub async fn run(mut self) -> Result<(), SessionError> {
         let mut tasks = FuturesUnordered::new();
 
+        // sessions without subscriptions are closed after the idle timeout
+        let idle_timeout = self.config.limits.idle_timeout.map(Duration::from_millis);
+        let idle = tokio::time::sleep(idle_timeout.unwrap_or_default());
+        tokio::pin!(idle);
+
         loop {
             tokio::select! {
-                Some(subscribe) = self.publisher.subscribed() => {
+                subscribe = self.publisher.subscribed() => {
+                    let Some(subscribe) = subscribe else {
+                        return Ok(());
+                    };
+
+                    if self.config.limits.max_subscriptions.is_some_and(|max| tasks.len() >= max) {
+                        log::warn!(
+                            "rejected subscribe: {}/{}, too many subscriptions",
+                            subscribe.namespace,
+                            subscribe.name
+                        );
+                        subscribe.close(ServeError::Closed(TOO_MANY_SUBSCRIPTIONS_CODE)).ok();
+                        continue;
+                    }
                     let this = self.clone();
 
                     tasks.push(async move {
                         let info = subscribe.clone();
                         log::info!("serving subscribe: {:?}", info);
+                        let _registration =
+                            this.sessions.subscribe(this.stats.id(), &info.namespace, &info.name);
+                        let qlog = this.qlog.clone();
+                        qlog.subscribe(&info.namespace, &info.name);
 
-                        if let Err(err) = this.serve(subscribe).await {
-                            log::warn!("failed serving subscribe: {:?}, error: {}", info, err)
+                        let res = this.serve(subscribe).await;
+                        if let Err(err) = &res {
+                            log::warn!("failed serving subscribe: {:?}, error: {}", info, err);
+                            if let Some(err @ SessionError::Write(_)) = err.downcast_ref() {
+                                let reason = err.to_string();
+                                qlog.stream_reset(&info.namespace, &info.name, err.code(), reason);
+                            }
                         }
+                        let error = res.err().map(|err| err.to_string());
+                        qlog.subscribe_done(&info.namespace, &info.name, error);
                     })
                 },
-                _= tasks.next(), if !tasks.is_empty() => {},
-                else => return Ok(()),
+                _= tasks.next(), if !tasks.is_empty() => {
+                    if let (Some(timeout), true) = (idle_timeout, tasks.is_empty()) {
+                        idle.as_mut().reset(tokio::time::Instant::now() + timeout);
+                    }
+                },
+                _ = &mut idle, if idle_timeout.is_some() && tasks.is_empty() => {
+                    log::info!("closing idle MoQ session");
+                    self.sessions.close(self.stats.id(), IDLE_CODE, "idle timeout");
+                    return Ok(());
+                },
+                _ = self.drain.wait() => break,
             };
         }
+
+        // the subscriptions end with the tracks, once the ingest stopped
+        while tasks.next().await.is_some() {}
+        Ok(())
     }
 
     async fn serve(self, subscribe: Subscribed) -> Result<(), anyhow::Error> {
+        // each broadcast has its own forwarding and keyframe request settings
+        let settings = self
+            .locals
+            .settings(&subscribe.namespace)
+            .unwrap_or_default();
+
+        // subscribing to keyframe-request (or keyframe-request/<rendition>) requests a keyframe,
+        // the subscription ends right away
+        let rendition = match subscribe.name.as_str() {
+            "keyframe-request" => Some(None),
+            name => name
+                .strip_prefix("keyframe-request/")
+                .map(|rendition| Some(rendition.to_string())),
+        };
+        if let Some(rendition) = rendition {
+            if let Some(rendition) = &rendition {
+                let catalog = self.catalog(&subscribe.namespace).await?;
+                if !catalog
+                    .renditions
+                    .iter()
+                    .any(|r| r.name.as_ref() == Some(rendition))
+                {
+                    return Err(ServeError::NotFound.into());
+                }
+            }
+            if let Some(keyframes) = &settings.keyframes {
+                keyframes.for_rendition(rendition).request();
+            }
+            let (writer, reader) =
+                Track::new(subscribe.namespace.clone(), subscribe.name.clone()).produce();
+            drop(writer);
+            return Ok(subscribe.serve(reader).await?);
+        }
+
+        if let Some(track) = subscribe.name.strip_suffix("/auto") {
+            if let Some(local) = self.locals.route(&subscribe.namespace) {
+                log::info!("serving auto rendition of: {}", track);
+
+                let track = track.to_string();
+                let (writer, reader) =
+                    Track::new(subscribe.namespace.clone(), subscribe.name.clone()).produce();
+                let mut auto = Auto::new(local, self.stats.clone());
+                if let Some(egress) = &self.config.egress {
+                    auto = auto.with_egress(egress.clone());
+                }
+                self.qlog.priorities(reader.clone());
+                return forward(subscribe, reader, auto.forward(&track, writer.stream(0)?)).await;
+            }
+        }
+
+        if let Some(cache) = self.locals.cache(&subscribe.namespace, &subscribe.name) {
+            log::info!("serving from cache: {}", subscribe.name);
+
+            // each subscriber gets its own copy of the track, starting at the join position,
+            // so frames can be dropped depending on the subscriber
+            let name = subscribe.name.clone();
+            let (writer, reader) = Track::new(subscribe.namespace.clone(), name.clone()).produce();
+
+            let config = settings.forward;
+
+            // keyframe requests for the track's rendition
+            let keyframes = match is_video(&name) {
+                true => self.locals.keyframes(&subscribe.namespace, &name).await?,
+                false => None,
+            };
+            // subscribers joining at the live edge would otherwise wait for the next keyframe
+            if config.join == JoinMode::LiveEdge {
+                if let Some(keyframes) = &keyframes {
+                    keyframes.request();
+                }
+            }
+
+            let sink = Sink::new(writer, cache.mode)?;
+            let mut forwarder = Forwarder::new(
+                cache.reader(config.join),
+                sink,
+                self.stats.clone(),
+                config.join,
+            )
+            .with_priority(config.priority)
+            .with_qlog(self.qlog.clone(), name.clone());
+
+            if let Some(egress) = &self.config.egress {
+                forwarder = forwarder.with_egress(egress.clone());
+            }
+
+            if let Some(dir) = &self.config.logging.send_log_dir {
+                let format = self.config.logging.send_log_format;
+                let id = self.stats.id();
+                let send_log = SendLog::create(dir, format, id, &subscribe.namespace, &name);
+                forwarder = forwarder.with_send_log(send_log);
+            }
+
+            match (cache.mode, config.deadline, config.drop_budget) {
+                (CacheMode::Objects, Some(deadline), _) => {
+                    let local = self
+                        .locals
+                        .route(&subscribe.namespace)
+                        .context("missing broadcast")?;
+                    let filter = deadline.filter(self.stats.clone(), local, &name).await?;
+                    forwarder = forwarder.with_deadline(filter);
+                }
+                (CacheMode::Stream | CacheMode::Groups, _, Some(budget)) if is_video(&name) => {
+                    let dropper = FrameDropper::new(budget, self.stats.clone(), keyframes);
+                    forwarder = forwarder.with_dropper(dropper);
+                }
+                _ => {}
+            }
+
+            self.qlog.priorities(reader.clone());
+            return forward(subscribe, reader, forwarder.run()).await;
+        }
+
+        // tracks that aren't cached, like the init and catalog tracks or datagram tracks
         if let Some(mut local) = self.locals.route(&subscribe.namespace) {
             if let Some(track) = local.subscribe(&subscribe.name) {
                 log::info!("serving from local: {:?}", track.info);
+
+                // datagrams are relayed through the egress limits
+                if let Some(egress) = &self.config.egress {
+                    if let TrackReaderMode::Datagrams(datagrams) = track.mode().await? {
+                        let (writer, reader) =
+                            Track::new(subscribe.namespace.clone(), subscribe.name.clone())
+                                .produce();
+                        self.qlog.priorities(reader.clone());
+                        let relay = egress.relay_datagrams(datagrams, writer.datagrams()?);
+                        return forward(subscribe, reader, relay).await;
+                    }
+                }
+                self.qlog.priorities(track.clone());
                 return Ok(subscribe.serve(track).await?);
             }
         }
 
         Err(ServeError::NotFound.into())
     }
+
+    async fn catalog(&self, namespace: &str) -> anyhow::Result<Catalog> {
+        let mut local = self.locals.route(namespace).context("missing broadcast")?;
+        read_catalog(&mut local).await
+    }
+}
+
+// Serves the subscriber's copy of a track while the forwarder fills it.
+// Once the forwarder is done, the copy is closed and the subscription ends after
+// the remaining objects were sent.
+async fn forward(
+    subscribe: Subscribed,
+    reader: TrackReader,
+    forwarder: impl Future<Output = anyhow::Result<()>>,
+) -> anyhow::Result<()> {
+    let serve = subscribe.serve(reader);
+    tokio::pin!(serve);
+    tokio::select! {
+        res = &mut serve => return Ok(res?),
+        res = forwarder => res?,
+    }
+    Ok(serve.await?)
+}
+
+// tracks carrying frames, as opposed to e.g. the init or catalog track
+fn is_video(name: &str) -> bool {
+    name == "video" || name.starts_with("video/")
 }
@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use serde_json::{json, Value};
+
+use crate::stats::SessionStats;
+
+// QUIC application error code used to close sessions kicked through the admin API (MoQ "no error")
+pub const KICKED_CODE: u32 = 0x0;
+
+struct Session {
+    connection: quinn::Connection,
+    stats: SessionStats,
+    remote: SocketAddr,
+    established: Instant,
+    // the subscriptions being served, by id
+    subscriptions: HashMap<u64, (String, String)>,
+    next_subscription: u64,
+}
+
+// The established sessions and their subscriptions, for the admin API.
+#[derive(Clone, Default)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<usize, Session>>>,
+}
+
+impl Sessions {
+    // the session is listed until the registration is dropped
+    pub fn register(
+        &self,
+        stats: SessionStats,
+        connection: quinn::Connection,
+    ) -> SessionRegistration {
+        let id = stats.id();
+        let session = Session {
+            remote: connection.remote_address(),
+            connection,
+            stats,
+            established: Instant::now(),
+            subscriptions: HashMap::new(),
+            next_subscription: 0,
+        };
+        self.sessions.lock().unwrap().insert(id, session);
+        SessionRegistration {
+            sessions: self.clone(),
+            id,
+        }
+    }
+
+    // the subscription is listed until the registration is dropped
+    pub fn subscribe(
+        &self,
+        session: usize,
+        namespace: &str,
+        name: &str,
+    ) -> SubscriptionRegistration {
+        let mut sessions = self.sessions.lock().unwrap();
+        let id = match sessions.get_mut(&session) {
+            Some(session) => {
+                let id = session.next_subscription;
+                session.next_subscription += 1;
+                let track = (namespace.to_string(), name.to_string());
+                session.subscriptions.insert(id, track);
+                id
+            }
+            None => 0,
+        };
+        SubscriptionRegistration {
+            sessions: self.clone(),
+            session,
+            id,
+        }
+    }
+
+    pub fn list(&self) -> Value {
+        let sessions = self.sessions.lock().unwrap();
+        let mut list: Vec<(&usize, &Session)> = sessions.iter().collect();
+        list.sort_by_key(|(id, _)| **id);
+        list.into_iter()
+            .map(|(id, session)| {
+                let stats = session.stats.get();
+                let subscriptions: Vec<Value> = session
+                    .subscriptions
+                    .values()
+                    .map(|(namespace, name)| json!({ "namespace": namespace, "name": name }))
+                    .collect();
+                json!({
+                    "id": id,
+                    "remote": session.remote.to_string(),
+                    "established_secs": session.established.elapsed().as_secs(),
+                    "subscriptions": subscriptions,
+                    "stats": {
+                        "rtt_ms": stats.rtt.as_secs_f64() * 1000.0,
+                        "cwnd": stats.cwnd,
+                        "sent_packets": stats.sent_packets,
+                        "lost_packets": stats.lost_packets,
+                        "loss": stats.loss,
+                        "delivery_rate": stats.delivery_rate,
+                        "queue_delay_ms": stats.queue_delay.as_secs_f64() * 1000.0,
+                    },
+                })
+            })
+            .collect()
+    }
+
+    // closes a session, returns false if there is no such session
+    pub fn close(&self, id: usize, code: u32, reason: &str) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        let Some(session) = sessions.get(&id) else {
+            return false;
+        };
+        log::info!("closing session {} ({}): {}", id, session.remote, reason);
+        session
+            .connection
+            .close(quinn::VarInt::from_u32(code), reason.as_bytes());
+        true
+    }
+
+    pub fn kick(&self, id: usize) -> bool {
+        self.close(id, KICKED_CODE, "kicked")
+    }
+}
+
+pub struct SessionRegistration {
+    sessions: Sessions,
+    id: usize,
+}
+
+impl Drop for SessionRegistration {
+    fn drop(&mut self) {
+        self.sessions.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+pub struct SubscriptionRegistration {
+    sessions: Sessions,
+    session: usize,
+    id: u64,
+}
+
+impl Drop for SubscriptionRegistration {
+    fn drop(&mut self) {
+        let mut sessions = self.sessions.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(&self.session) {
+            session.subscriptions.remove(&self.id);
+        }
+    }
+}
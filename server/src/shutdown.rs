@@ -0,0 +1,72 @@
+use std::time::Duration;
+
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+// QUIC application error code used to close sessions after draining (MoQ "no error").
+// The GOAWAY message would have to be written to the session's control stream, which the
+// transport keeps private, so the replacement server's URL, if any, is sent as the reason
+// of the connection close instead.
+pub const GOAWAY_CODE: u32 = 0x0;
+
+// waits for SIGTERM or SIGINT
+pub async fn signal_received() -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => log::info!("received SIGTERM"),
+        _ = interrupt.recv() => log::info!("received SIGINT"),
+    }
+    Ok(())
+}
+
+// Notifies the server and its sessions that the process is shutting down.
+#[derive(Clone)]
+pub struct Drain {
+    draining: watch::Receiver<bool>,
+    // where subscribers should reconnect to
+    pub goaway_url: Option<String>,
+    // how long sessions may take to finish their subscriptions once draining started
+    grace: Duration,
+}
+
+impl Drain {
+    pub fn new(goaway_url: Option<String>, grace: Duration) -> (watch::Sender<bool>, Self) {
+        let (sender, draining) = watch::channel(false);
+        (
+            sender,
+            Self {
+                draining,
+                goaway_url,
+                grace,
+            },
+        )
+    }
+
+    pub fn is_draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    // resolves once draining started, never if the sender is dropped before that
+    pub async fn wait(&mut self) {
+        while !*self.draining.borrow_and_update() {
+            if self.draining.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    // resolves once the grace period after draining started is over
+    pub async fn expired(&mut self) {
+        self.wait().await;
+        tokio::time::sleep(self.grace).await;
+    }
+
+    // closes a drained session, pointing the subscriber at the replacement server
+    pub fn close(&self, connection: &quinn::Connection) {
+        let reason = self.goaway_url.as_deref().unwrap_or("shutting down");
+        connection.close(quinn::VarInt::from_u32(GOAWAY_CODE), reason.as_bytes());
+    }
+}
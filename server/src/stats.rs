@@ -0,0 +1,199 @@
+use std::{
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+const LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+// overhead of a 1-RTT packet: header with an 8 byte connection id and packet number, AEAD tag
+const PACKET_OVERHEAD: u64 = 1 + 8 + 4 + 16;
+// overhead of a STREAM frame: type, stream id, offset and length
+const STREAM_FRAME_OVERHEAD: u64 = 1 + 4 + 8 + 2;
+// size of an ACK frame with a single range
+const ACK_FRAME_SIZE: u64 = 1 + 4 + 2 + 1 + 1;
+// bytes sent in a sample interval without any data enqueued, i.e. ACKs and keep-alives only
+const IDLE_BYTES: u64 = 2 * 1200;
+
+// A sample of the congestion statistics of a QUIC connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub rtt: Duration,
+    // congestion window in bytes
+    pub cwnd: u64,
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    // fraction of the packets sent since the previous sample that were lost
+    pub loss: f64,
+    // bytes sent and not lost per second since the previous sample,
+    // limited by the rate the application is sending at
+    pub delivery_rate: f64,
+    // estimated time it takes to send the data queued in the session
+    pub queue_delay: Duration,
+}
+
+impl Stats {
+    // estimated capacity of the path in bytes per second, based on the congestion window and the RTT
+    pub fn capacity(&self) -> f64 {
+        let rtt = self.rtt.max(Duration::from_millis(1));
+        self.cwnd as f64 / rtt.as_secs_f64()
+    }
+
+    // estimated time until data written now arrives at the peer
+    pub fn one_way_delay(&self) -> Duration {
+        self.queue_delay + self.rtt / 2
+    }
+}
+
+// Congestion statistics of a session, sampled periodically from the QUIC connection.
+#[derive(Clone)]
+pub struct SessionStats {
+    connection: quinn::Connection,
+    latest: watch::Receiver<Stats>,
+    sender: Arc<watch::Sender<Stats>>,
+
+    // bytes handed to the session by the forwarders
+    enqueued: Arc<AtomicU64>,
+    // estimated stream bytes sent that weren't enqueued, reset whenever the queue is empty
+    // so errors of the estimate don't accumulate
+    sent_offset: Arc<AtomicI64>,
+}
+
+impl SessionStats {
+    pub fn new(connection: quinn::Connection) -> Self {
+        let (sender, latest) = watch::channel(Stats::default());
+        let sent_offset = stream_bytes(&connection);
+        Self {
+            connection,
+            latest,
+            sender: Arc::new(sender),
+            enqueued: Default::default(),
+            sent_offset: Arc::new(AtomicI64::new(sent_offset)),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.connection.stable_id()
+    }
+
+    // the latest sample
+    pub fn get(&self) -> Stats {
+        *self.latest.borrow()
+    }
+
+    // records data handed to the session, so the queue delay can be estimated
+    pub fn enqueued(&self, bytes: usize) {
+        self.enqueued.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    // Estimates the time it takes to send the data queued in the session, i.e. the data
+    // enqueued by the forwarders but not sent yet, at the path's estimated capacity.
+    // The stream bytes sent are estimated from the UDP bytes without retransmissions and
+    // packet and frame overhead, and rebased whenever the connection is idle.
+    pub fn queue_delay(&self) -> Duration {
+        let stats = self.connection.stats();
+        let queued = self.queued();
+
+        let rtt = stats.path.rtt.max(Duration::from_millis(1));
+        let capacity = stats.path.cwnd as f64 / rtt.as_secs_f64();
+        Duration::from_secs_f64(queued as f64 / capacity.max(1.0))
+    }
+
+    fn queued(&self) -> u64 {
+        let sent = stream_bytes(&self.connection) - self.sent_offset.load(Ordering::Relaxed);
+        let enqueued = self.enqueued.load(Ordering::Relaxed) as i64;
+        enqueued.saturating_sub(sent).max(0) as u64
+    }
+
+    // samples the connection's statistics until it is closed
+    pub async fn run(self) {
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        let mut previous = self.connection.stats();
+        let mut sampled = Instant::now();
+        let mut logged = Instant::now();
+        let mut enqueued = self.enqueued.load(Ordering::Relaxed);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {},
+                _ = self.connection.closed() => return,
+            }
+
+            let current = self.connection.stats();
+            let now = Instant::now();
+            let elapsed = now.duration_since(sampled).as_secs_f64().max(f64::EPSILON);
+
+            let sent = current.path.sent_packets - previous.path.sent_packets;
+            let lost = current.path.lost_packets - previous.path.lost_packets;
+            let delivered = (current.udp_tx.bytes - previous.udp_tx.bytes)
+                .saturating_sub(current.path.lost_bytes - previous.path.lost_bytes);
+
+            // nothing was enqueued and hardly anything sent, so the queue is empty
+            let previously_enqueued = enqueued;
+            enqueued = self.enqueued.load(Ordering::Relaxed);
+            let idle = current.udp_tx.bytes - previous.udp_tx.bytes <= IDLE_BYTES;
+            if enqueued == previously_enqueued && idle {
+                let offset = stream_bytes(&self.connection) - enqueued as i64;
+                self.sent_offset.store(offset, Ordering::Relaxed);
+            }
+
+            let stats = Stats {
+                rtt: current.path.rtt,
+                cwnd: current.path.cwnd,
+                sent_packets: current.path.sent_packets,
+                lost_packets: current.path.lost_packets,
+                loss: if sent > 0 {
+                    lost as f64 / sent as f64
+                } else {
+                    0.0
+                },
+                delivery_rate: delivered as f64 / elapsed,
+                queue_delay: self.queue_delay(),
+            };
+            self.sender.send_replace(stats);
+
+            if now.duration_since(logged) >= LOG_INTERVAL {
+                log::info!(
+                    target: "stats",
+                    "conn={} rtt_ms={:.1} cwnd={} sent_packets={} lost_packets={} loss={:.4} delivery_rate={:.0} capacity={:.0} queue_delay_ms={:.1}",
+                    self.id(),
+                    stats.rtt.as_secs_f64() * 1000.0,
+                    stats.cwnd,
+                    stats.sent_packets,
+                    stats.lost_packets,
+                    stats.loss,
+                    stats.delivery_rate,
+                    stats.capacity(),
+                    stats.queue_delay.as_secs_f64() * 1000.0,
+                );
+                logged = now;
+            }
+
+            previous = current;
+            sampled = now;
+        }
+    }
+}
+
+// estimated stream bytes sent on the connection: the UDP bytes sent without the lost packets,
+// which are retransmitted, and the packet and frame overhead
+fn stream_bytes(connection: &quinn::Connection) -> i64 {
+    let stats = connection.stats();
+    let packets = stats
+        .udp_tx
+        .datagrams
+        .saturating_sub(stats.path.lost_packets);
+    let overhead = packets * PACKET_OVERHEAD
+        + stats.frame_tx.stream * STREAM_FRAME_OVERHEAD
+        + stats.frame_tx.acks * ACK_FRAME_SIZE;
+    stats
+        .udp_tx
+        .bytes
+        .saturating_sub(stats.path.lost_bytes)
+        .saturating_sub(overhead) as i64
+}
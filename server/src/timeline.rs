@@ -0,0 +1,294 @@
+use std::{cell::RefCell, rc::Rc, time::Duration};
+
+use bytes::BytesMut;
+
+use crate::video::parse_timescale;
+
+// jumps forward by more than this many seconds are treated as a discontinuity
+const MAX_GAP_SECS: u64 = 5;
+// used until the init segment of the rendition was seen
+const DEFAULT_TIMESCALE: u32 = 90_000;
+
+// offsets of the timestamps in a frame item
+// (0x01, keyframe u8, frame type u8, availability u64, dts u64, pts u64, ...)
+const DTS_OFFSET: usize = 1 + 1 + 1 + 8;
+const PTS_OFFSET: usize = DTS_OFFSET + 8;
+
+// The offset shared by the renditions of a broadcast. The rendition that sees a restart
+// first sets the offset, the others take it over once they see the same restart, so the
+// renditions stay aligned for ABR switches.
+#[derive(Clone, Default)]
+pub struct BroadcastTimeline {
+    shared: Rc<RefCell<Shared>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    // in nanoseconds, since the renditions may use different timescales
+    offset: i128,
+    // increased whenever a rendition sets the offset
+    generation: u64,
+    // the end of the latest frame of any rendition, in nanoseconds
+    end: i128,
+}
+
+// Keeps the timestamps of a rendition monotonic when the source loops or restarts.
+// The decode timestamps of the input are expected to increase; when they jump backward
+// or too far forward, the following timestamps are offset to continue one frame after
+// the previous frame of any rendition of the broadcast, so the mappings (e.g. priorities
+// based on the decode time) never see time going backward.
+pub struct Timeline {
+    timescale: u32,
+    // added to the timestamps of the input
+    offset: i128,
+    // the last rebased decode timestamp and the duration of the last frame
+    last: Option<(u64, u64)>,
+    broadcast: BroadcastTimeline,
+    // the generation of the broadcast's offset this rendition uses
+    generation: u64,
+    pub discontinuities: u64,
+}
+
+// a timeline of its own, for a broadcast with a single rendition
+impl Default for Timeline {
+    fn default() -> Self {
+        Self::new(BroadcastTimeline::default())
+    }
+}
+
+impl Timeline {
+    pub fn new(broadcast: BroadcastTimeline) -> Self {
+        Self {
+            timescale: DEFAULT_TIMESCALE,
+            offset: 0,
+            last: None,
+            broadcast,
+            generation: 0,
+            discontinuities: 0,
+        }
+    }
+
+    pub fn init(&mut self, init: &[u8]) {
+        if let Some(timescale) = parse_timescale(init) {
+            self.timescale = timescale;
+        }
+    }
+
+    // the decode time of a rebased frame item, None for init segments
+    pub fn media_time(&self, item: &[u8]) -> Option<Duration> {
+        if item.first() != Some(&0x01) || item.len() < DTS_OFFSET + 8 {
+            return None;
+        }
+        let dts = read_u64(item, DTS_OFFSET);
+        Some(Duration::from_secs_f64(dts as f64 / self.timescale as f64))
+    }
+
+    // Rebases the timestamps of a frame item in place.
+    // Returns whether the frame follows a discontinuity.
+    pub fn rebase(&mut self, item: &mut BytesMut) -> anyhow::Result<bool> {
+        if item.len() < PTS_OFFSET + 8 {
+            return Err(anyhow::anyhow!("Frame item too short"));
+        }
+        let dts = read_u64(item, DTS_OFFSET);
+        let pts = read_u64(item, PTS_OFFSET);
+
+        let mut rebased = offset(dts, self.offset);
+        let mut discontinuity = false;
+        if let Some((last, duration)) = self.last {
+            let max_gap = MAX_GAP_SECS * self.timescale as u64;
+            if rebased < last || rebased > last.saturating_add(max_gap) {
+                let next = last + duration;
+                self.offset = self.restart(dts, next);
+                self.discontinuities += 1;
+                discontinuity = true;
+                log::info!(
+                    "timestamp discontinuity: dts={} expected={} offset={} discontinuities={}",
+                    dts,
+                    next,
+                    self.offset,
+                    self.discontinuities
+                );
+                rebased = offset(dts, self.offset);
+            }
+        }
+
+        let duration = match self.last {
+            Some((last, _)) if rebased > last => rebased - last,
+            Some((_, duration)) => duration,
+            // until there are two frames, assume 30 fps
+            None => (self.timescale as u64 / 30).max(1),
+        };
+        self.last = Some((rebased, duration));
+
+        // the other renditions continue after this frame when they restart
+        let end = self.nanos(rebased as i128 + duration as i128);
+        let mut shared = self.broadcast.shared.borrow_mut();
+        shared.end = shared.end.max(end);
+
+        item[DTS_OFFSET..DTS_OFFSET + 8].copy_from_slice(&rebased.to_be_bytes());
+        item[PTS_OFFSET..PTS_OFFSET + 8].copy_from_slice(&offset(pts, self.offset).to_be_bytes());
+        Ok(discontinuity)
+    }
+
+    // The offset after a restart at the given decode time: the one another rendition set
+    // for the same restart, or one continuing after the latest frame of all renditions.
+    fn restart(&mut self, dts: u64, next: u64) -> i128 {
+        let mut shared = self.broadcast.shared.borrow_mut();
+        if shared.generation > self.generation {
+            let offset = self.timestamp(shared.offset);
+            // unless the rendition would go backward, e.g. if it restarted on its own
+            if dts as i128 + offset >= next as i128 {
+                self.generation = shared.generation;
+                return offset;
+            }
+        }
+
+        let next = (next as i128).max(self.timestamp(shared.end));
+        let offset = next - dts as i128;
+        shared.offset = self.nanos(offset);
+        shared.generation += 1;
+        self.generation = shared.generation;
+        offset
+    }
+
+    // rounded down, and back up below, so a timestamp converted back and forth is unchanged
+    fn nanos(&self, timestamp: i128) -> i128 {
+        (timestamp * 1_000_000_000).div_euclid(self.timescale as i128)
+    }
+
+    fn timestamp(&self, nanos: i128) -> i128 {
+        let timescale = self.timescale as i128;
+        (nanos * timescale + 999_999_999).div_euclid(1_000_000_000)
+    }
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn offset(timestamp: u64, offset: i128) -> u64 {
+    (timestamp as i128 + offset).clamp(0, u64::MAX as i128) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a frame item with the given decode time, presented one frame later
+    fn frame(dts: u64) -> BytesMut {
+        let mut item = BytesMut::from(&[0x01, 0, 0][..]);
+        item.extend_from_slice(&0u64.to_be_bytes());
+        item.extend_from_slice(&dts.to_be_bytes());
+        item.extend_from_slice(&(dts + 3000).to_be_bytes());
+        item.extend_from_slice(&0u32.to_be_bytes());
+        item
+    }
+
+    // rebases the frames, returns their decode times and whether they are discontinuities
+    fn rebase(timeline: &mut Timeline, dts: &[u64]) -> Vec<(u64, bool)> {
+        dts.iter()
+            .map(|dts| {
+                let mut item = frame(*dts);
+                let discontinuity = timeline.rebase(&mut item).unwrap();
+                assert_eq!(
+                    read_u64(&item, PTS_OFFSET),
+                    read_u64(&item, DTS_OFFSET) + 3000
+                );
+                (read_u64(&item, DTS_OFFSET), discontinuity)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn restart_with_lower_decode_time() {
+        let mut timeline = Timeline::default();
+        let rebased = rebase(&mut timeline, &[90_000, 93_000, 96_000, 0, 3000]);
+        assert_eq!(
+            rebased,
+            vec![
+                (90_000, false),
+                (93_000, false),
+                (96_000, false),
+                (99_000, true),
+                (102_000, false)
+            ]
+        );
+    }
+
+    #[test]
+    fn restart_with_higher_decode_time() {
+        let mut timeline = Timeline::default();
+        // a jump forward by more than MAX_GAP_SECS
+        let rebased = rebase(&mut timeline, &[0, 3000, 6000, 900_000, 903_000]);
+        assert_eq!(
+            rebased,
+            vec![
+                (0, false),
+                (3000, false),
+                (6000, false),
+                (9000, true),
+                (12_000, false)
+            ]
+        );
+
+        // a smaller gap, e.g. after dropped frames, is kept
+        let rebased = rebase(&mut timeline, &[963_000]);
+        assert_eq!(rebased, vec![(72_000, false)]);
+    }
+
+    #[test]
+    fn several_restarts_in_a_row() {
+        let mut timeline = Timeline::default();
+        let rebased = rebase(&mut timeline, &[500_000, 503_000, 0, 3000, 0, 3000, 0]);
+        assert_eq!(
+            rebased,
+            vec![
+                (500_000, false),
+                (503_000, false),
+                (506_000, true),
+                (509_000, false),
+                (512_000, true),
+                (515_000, false),
+                (518_000, true)
+            ]
+        );
+        assert_eq!(timeline.discontinuities, 3);
+    }
+
+    #[test]
+    fn renditions_rebased_together() {
+        let broadcast = BroadcastTimeline::default();
+        let mut high = Timeline::new(broadcast.clone());
+        let mut low = Timeline::new(broadcast);
+
+        // the high rendition got one more frame out before the encoder restarted
+        rebase(&mut high, &[90_000, 93_000, 96_000]);
+        rebase(&mut low, &[90_000, 93_000]);
+
+        // both continue after the latest frame of the broadcast, with the same timestamps
+        let restarted = [0, 3000];
+        assert_eq!(
+            rebase(&mut low, &restarted),
+            vec![(99_000, true), (102_000, false)]
+        );
+        assert_eq!(
+            rebase(&mut high, &restarted),
+            vec![(99_000, true), (102_000, false)]
+        );
+    }
+
+    #[test]
+    fn renditions_with_different_timescales_rebased_together() {
+        let broadcast = BroadcastTimeline::default();
+        let mut high = Timeline::new(broadcast.clone());
+        let mut low = Timeline::new(broadcast);
+        low.timescale = 1000;
+
+        rebase(&mut high, &[90_000, 93_000]);
+        rebase(&mut low, &[1000, 1033]);
+
+        // both continue at about 1.067s, after the latest frame of the broadcast
+        assert_eq!(rebase(&mut high, &[0]), vec![(96_000, true)]);
+        assert_eq!(rebase(&mut low, &[0]), vec![(1067, true)]);
+    }
+}
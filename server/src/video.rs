@@ -1,6 +1,6 @@
+use crate::broadcast::Rendition;
 use anyhow::Result;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use moq_transport::serve::TracksWriter;
 use std::{
     io::{Cursor, Seek, SeekFrom},
     time::{Duration, SystemTime, UNIX_EPOCH},
@@ -21,6 +21,8 @@ pub enum FrameType {
 pub struct Frame {
     pub is_keyframe: bool,
     pub frame_type: FrameType,
+    // the first frame after a discontinuity in the input, e.g. after the encoder restarted
+    pub discontinuity: bool,
     pub availability_time: SystemTime,
     pub decode_time: u64,
     pub presentation_time: u64,
@@ -41,7 +43,7 @@ impl TryFrom<u8> for FrameType {
 }
 
 pub trait VideoStreamer {
-    fn new(namespace: TracksWriter) -> anyhow::Result<Self>
+    fn new(namespace: Rendition<'_>) -> anyhow::Result<Self>
     where
         Self: Sized;
     fn stream(&mut self, buf: &mut BytesMut) -> Result<()>;
@@ -49,34 +51,39 @@ pub trait VideoStreamer {
 
 const FRAME_HEADER: usize = 1 + 1 + 8 + 8 + 8 + 4;
 pub fn next_item(buf: &mut BytesMut) -> anyhow::Result<bool> {
-    let mut peek = Cursor::new(buf.chunk());
+    Ok(item_size(buf)?.is_some())
+}
+
+// returns the size of the next item as soon as its header was read,
+// or None if the buffer doesn't contain all of the header yet
+pub fn declared_item_size(buf: &[u8]) -> anyhow::Result<Option<usize>> {
+    let mut peek = Cursor::new(buf);
     if peek.remaining() < 1 {
-        return Ok(false);
+        return Ok(None);
     }
     let segment_type = peek.get_u8();
-    match segment_type {
+    let size = match segment_type {
         0x00 => {
             if peek.remaining() < 4 {
-                return Ok(false);
-            }
-            let size = peek.get_u32() as usize;
-            if peek.remaining() < size {
-                return Ok(false);
+                return Ok(None);
             }
+            peek.get_u32() as usize
         }
         0x01 => {
             if peek.remaining() < FRAME_HEADER {
-                return Ok(false);
+                return Ok(None);
             }
             peek.seek(SeekFrom::Current((FRAME_HEADER - 4) as i64))?;
-            let size = peek.get_u32() as usize;
-            if peek.remaining() < size {
-                return Ok(false);
-            }
+            peek.get_u32() as usize
         }
         _ => return Err(anyhow::anyhow!("Unknown segment type")),
-    }
-    Ok(true)
+    };
+    Ok(Some(peek.position() as usize + size))
+}
+
+// returns the size of the next item, or None if the buffer doesn't contain all of it yet
+pub fn item_size(buf: &[u8]) -> anyhow::Result<Option<usize>> {
+    Ok(declared_item_size(buf)?.filter(|size| *size <= buf.len()))
 }
 
 pub fn parse_item(buf: &mut BytesMut) -> anyhow::Result<MediaStreamItem> {
@@ -97,7 +104,9 @@ pub fn parse_item(buf: &mut BytesMut) -> anyhow::Result<MediaStreamItem> {
 
 pub fn parse_frame(buf: &mut BytesMut) -> anyhow::Result<Frame> {
     let is_keyframe = buf.get_u8() != 0;
-    let frame_type = FrameType::try_from(buf.get_u8())?;
+    let frame_type = buf.get_u8();
+    let discontinuity = frame_type & DISCONTINUITY_FLAG != 0;
+    let frame_type = FrameType::try_from(frame_type & !DISCONTINUITY_FLAG)?;
     let availability_time = UNIX_EPOCH + Duration::from_nanos(buf.get_u64());
     let decode_time = buf.get_u64();
     let presentation_time = buf.get_u64();
@@ -107,6 +116,7 @@ pub fn parse_frame(buf: &mut BytesMut) -> anyhow::Result<Frame> {
     Ok(Frame {
         is_keyframe,
         frame_type,
+        discontinuity,
         availability_time,
         decode_time,
         presentation_time,
@@ -115,7 +125,11 @@ pub fn parse_frame(buf: &mut BytesMut) -> anyhow::Result<Frame> {
 }
 
 pub fn serialize_frame(frame: &Frame, buf: &mut BytesMut) -> Result<()> {
-    buf.put_u8(frame.frame_type as u8);
+    let flags = match frame.discontinuity {
+        true => DISCONTINUITY_FLAG,
+        false => 0,
+    };
+    buf.put_u8(frame.frame_type as u8 | flags);
     buf.extend_from_slice(
         &frame
             .availability_time
@@ -140,3 +154,78 @@ pub fn serialize_frame_info(info: FrameInfo, buf: &mut BytesMut) -> Result<()> {
     buf.extend_from_slice(&info.dts.to_be_bytes());
     Ok(())
 }
+
+// Set in the frame type byte of the first frame after a discontinuity (a restart of the
+// encoder or a jump of the timestamps), both in the ingest framing and in serialized frames.
+// Players should reset their decoder.
+pub const DISCONTINUITY_FLAG: u8 = 0x40;
+
+// Set in the frame type byte of a serialized frame that can't be decoded by the subscriber,
+// because it joined at the live edge after the keyframe the frame depends on.
+pub const UNDECODABLE_FLAG: u8 = 0x80;
+
+pub fn mark_undecodable(payload: &[u8]) -> Bytes {
+    let mut payload = BytesMut::from(payload);
+    if let Some(frame_type) = payload.first_mut() {
+        *frame_type |= UNDECODABLE_FLAG;
+    }
+    payload.freeze()
+}
+
+pub struct FrameHeader {
+    pub frame_type: FrameType,
+    pub availability_time: SystemTime,
+    pub decode_time: u64,
+    pub presentation_time: u64,
+}
+
+// parses the header written by serialize_frame
+pub fn parse_frame_header(mut payload: &[u8]) -> Result<FrameHeader> {
+    if payload.remaining() < 1 + 8 + 8 + 8 {
+        return Err(anyhow::anyhow!("Frame payload too short"));
+    }
+    let frame_type = payload.get_u8() & !(UNDECODABLE_FLAG | DISCONTINUITY_FLAG);
+    let frame_type = FrameType::try_from(frame_type)?;
+    let availability_time = UNIX_EPOCH + Duration::from_nanos(payload.get_u64());
+    let decode_time = payload.get_u64();
+    let presentation_time = payload.get_u64();
+
+    Ok(FrameHeader {
+        frame_type,
+        availability_time,
+        decode_time,
+        presentation_time,
+    })
+}
+
+// returns the timescale of the first track found in the init segment (ftyp + moov)
+pub fn parse_timescale(init: &[u8]) -> Option<u32> {
+    let mut buf = init;
+    while buf.len() >= 8 {
+        let size = u32::from_be_bytes(buf[0..4].try_into().unwrap()) as usize;
+        let kind = &buf[4..8];
+        if size < 8 || size > buf.len() {
+            return None;
+        }
+        let body = &buf[8..size];
+        match kind {
+            b"moov" | b"trak" | b"mdia" => {
+                if let Some(timescale) = parse_timescale(body) {
+                    return Some(timescale);
+                }
+            }
+            b"mdhd" => {
+                // version (1) + flags (3) + creation and modification times
+                let offset = match body.first()? {
+                    0 => 4 + 4 + 4,
+                    _ => 4 + 8 + 8,
+                };
+                let timescale = body.get(offset..offset + 4)?;
+                return Some(u32::from_be_bytes(timescale.try_into().unwrap()));
+            }
+            _ => {}
+        }
+        buf = &buf[size..];
+    }
+    None
+}